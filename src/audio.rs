@@ -1,78 +1,432 @@
 use crate::drums::DrumSound;
-use crate::instrument::{CustomWaveform, InstrumentType};
-use crate::recorder::Note;
-use rodio::{OutputStream, Sink, Source};
+use crate::envelope::Envelope;
+use crate::instrument::InstrumentType;
+use crate::kit::{DrumKit, KitSounds};
+use crate::looper::Looper;
+use crate::midi_output::MidiQueue;
+use crate::mixer::{self, Mixer, Retired};
+use crate::recorder::{Note, Take};
+use crate::sequencer::{DrumPosition, PatternHits, StepSequencer};
+use crate::settings::Settings;
+use crate::sync::{SyncMessage, SyncMode};
+use crate::transport::{TimeSignature, TransportState};
+use log::warn;
+use rodio::OutputStream;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Duration;
 
+const COMMAND_QUEUE_SIZE: usize = 256;
+
+#[derive(Clone, Copy)]
+pub struct SequenceEvent {
+    pub at: Duration,
+    pub sound: SequenceSound,
+}
+
+#[derive(Clone, Copy)]
+pub enum SequenceSound {
+    Note {
+        /// MIDI note number
+        note: u8,
+        duration: Duration,
+        /// Plays with the mixer's current instrument when unset
+        instrument: Option<InstrumentType>,
+    },
+    Drum(DrumSound),
+}
+
+pub enum AudioCommand {
+    /// MIDI note and velocity, from 0 to 1
+    NoteOn(u8, f32),
+    NoteOff(u8),
+    DrumHit(DrumSound, f32),
+    SetInstrument(InstrumentType),
+    SetEnvelope(InstrumentType, Envelope),
+    SetLofi(bool),
+    /// Sample data for `DrumSound::Sample` hits, empty for the synth kit
+    SetKit(KitSounds),
+    PlaySequence {
+        events: Vec<SequenceEvent>,
+        length: Duration,
+        looping: bool,
+    },
+    StopSequence,
+    /// Replaces the loop station layers. The loop clock carries on from
+    /// where it is if the length hasn't changed, so layers stay in sync.
+    PlayLoops {
+        events: Vec<SequenceEvent>,
+        length: Duration,
+    },
+    StopLoops,
+    SetTempo(f32),
+    SetTimeSignature(TimeSignature),
+    SetMetronome {
+        enabled: bool,
+        volume: f32,
+    },
+    /// Starts the transport with clicks up to the given tick, whether or
+    /// not the metronome is on. The UI works out the tick so the recorder
+    /// starts capturing on the same bar line the clicks end on.
+    CountIn(u64),
+    /// Patterns for the step sequencer to play in order. With `at_bar`
+    /// set, a running arrangement only changes over at the next bar line.
+    SetDrums {
+        patterns: Vec<PatternHits>,
+        looping: bool,
+        at_bar: bool,
+    },
+    /// Starts or resumes the transport; sequences and loops only advance
+    /// while it is playing
+    TransportPlay,
+    TransportPause,
+    /// Rewinds the transport to the top and stops take playback
+    TransportStop,
+    /// Queue for notes to go out on a MIDI port, or None to stop
+    SetMidiOutput(Option<MidiQueue>),
+    /// Whether MIDI output replaces the internal synth or plays alongside
+    SetMidiOnly(bool),
+    SetSync(SyncMode),
+    /// Clock messages from the MIDI inputs
+    SetSyncInput(Consumer<SyncMessage>),
+}
+
+/// Commands that bring a fresh mixer in line with the saved settings.
+pub fn settings_commands(settings: &Settings) -> Vec<AudioCommand> {
+    let mut commands = vec![
+        AudioCommand::SetInstrument(settings.instrument),
+        AudioCommand::SetLofi(settings.lofi),
+        AudioCommand::SetMidiOnly(settings.midi_only),
+        AudioCommand::SetSync(settings.sync),
+        AudioCommand::SetMetronome {
+            enabled: settings.metronome,
+            volume: settings.metronome_volume,
+        },
+    ];
+    for (&instrument, &envelope) in &settings.envelopes {
+        commands.push(AudioCommand::SetEnvelope(instrument, envelope));
+    }
+    commands
+}
+
+/// Schedules every note and drum hit of a take at its recorded time,
+/// scaled by `speed`. Keyboard notes without an instrument of their own
+/// take the one last changed to before them, or the mixer's in takes that
+/// never recorded one.
+pub fn recording_sequence(take: &Take, speed: f32, looping: bool) -> AudioCommand {
+    let mut current = None;
+    let mut events = take
+        .notes
+        .iter()
+        .filter_map(|note| {
+            let sound = match *note {
+                Note::Key {
+                    duration,
+                    instrument,
+                    ..
+                } => SequenceSound::Note {
+                    note: note.pitch()?,
+                    duration: duration.div_f32(speed),
+                    instrument: instrument.or(current),
+                },
+                Note::Drum { drum, .. } => SequenceSound::Drum(drum),
+                Note::Instrument { instrument, .. } => {
+                    current = Some(instrument);
+                    return None;
+                }
+            };
+            Some(SequenceEvent {
+                at: note.timestamp().div_f32(speed),
+                sound,
+            })
+        })
+        .collect::<Vec<_>>();
+    events.sort_by_key(|event| event.at);
+
+    AudioCommand::PlaySequence {
+        events,
+        length: take.length().div_f32(speed),
+        looping,
+    }
+}
+
+/// Playback position published by the audio thread for the UI to draw.
+#[derive(Default)]
+pub struct PlaybackStatus {
+    playing: AtomicBool,
+    position: AtomicU64,
+    length: AtomicU64,
+    loop_position: AtomicU64,
+    loop_length: AtomicU64,
+    transport_state: AtomicU8,
+    transport_ticks: AtomicU64,
+    // Packed DrumPosition of the step sequencer, zero when silent
+    drum_position: AtomicU64,
+    // Bits of the followed clock's tempo, zero without one
+    sync_bpm: AtomicU32,
+}
+
+impl PlaybackStatus {
+    pub fn update(&self, playing: bool, position: u64, length: u64) {
+        self.playing.store(playing, Ordering::Relaxed);
+        self.position.store(position, Ordering::Relaxed);
+        self.length.store(length, Ordering::Relaxed);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    pub fn position(&self) -> Duration {
+        mixer::to_duration(self.position.load(Ordering::Relaxed))
+    }
+
+    pub fn length(&self) -> Duration {
+        mixer::to_duration(self.length.load(Ordering::Relaxed))
+    }
+
+    pub fn update_loops(&self, position: u64, length: u64) {
+        self.loop_position.store(position, Ordering::Relaxed);
+        self.loop_length.store(length, Ordering::Relaxed);
+    }
+
+    pub fn update_transport(&self, state: TransportState, ticks: u64) {
+        self.transport_state.store(state as u8, Ordering::Relaxed);
+        self.transport_ticks.store(ticks, Ordering::Relaxed);
+    }
+
+    pub fn transport_state(&self) -> TransportState {
+        TransportState::from_u8(self.transport_state.load(Ordering::Relaxed))
+    }
+
+    pub fn transport_ticks(&self) -> u64 {
+        self.transport_ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn update_drums(&self, position: Option<DrumPosition>) {
+        let packed = position.map_or(0, |p| {
+            1 << 48 | (p.entry as u64) << 32 | (p.slot as u64) << 16 | p.step as u64
+        });
+        self.drum_position.store(packed, Ordering::Relaxed);
+    }
+
+    pub fn drum_position(&self) -> Option<DrumPosition> {
+        match self.drum_position.load(Ordering::Relaxed) {
+            0 => None,
+            packed => Some(DrumPosition {
+                entry: (packed >> 32 & 0xFFFF) as usize,
+                slot: (packed >> 16 & 0xFFFF) as usize,
+                step: (packed & 0xFFFF) as usize,
+            }),
+        }
+    }
+
+    pub fn update_sync(&self, bpm: Option<f32>) {
+        self.sync_bpm
+            .store(bpm.map_or(0, f32::to_bits), Ordering::Relaxed);
+    }
+
+    /// Measured tempo of the MIDI clock being followed.
+    pub fn sync_bpm(&self) -> Option<f32> {
+        match self.sync_bpm.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f32::from_bits(bits)),
+        }
+    }
+
+    /// Position and length of the loop station, while it's running.
+    pub fn loop_playhead(&self) -> Option<(Duration, Duration)> {
+        match self.loop_length.load(Ordering::Relaxed) {
+            0 => None,
+            length => Some((
+                mixer::to_duration(self.loop_position.load(Ordering::Relaxed)),
+                mixer::to_duration(length),
+            )),
+        }
+    }
+}
+
 pub struct AudioEngine {
-    _stream: OutputStream,
-    sink: Sink,
-    current_instrument: InstrumentType,
+    commands: Producer<AudioCommand>,
+    // Buffers the mixer is done with, freed here rather than on its thread
+    retired: Consumer<Retired>,
+    status: Arc<PlaybackStatus>,
+    // Dropping the sender wakes the audio thread so it can close the stream
+    _shutdown: mpsc::Sender<()>,
 }
 
 impl AudioEngine {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let (stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
+        let (commands, queue) = RingBuffer::new(COMMAND_QUEUE_SIZE);
+        let (retire, retired) = RingBuffer::new(COMMAND_QUEUE_SIZE);
+        let status = Arc::new(PlaybackStatus::default());
+        let (shutdown, shutdown_rx) = mpsc::channel::<()>();
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<(), String>>();
 
-        Ok(AudioEngine {
-            _stream: stream,
-            sink,
-            current_instrument: InstrumentType::Sine,
-        })
+        let mixer = Mixer::new(queue, retire, Arc::clone(&status));
+        thread::Builder::new()
+            .name("devtunes-audio".to_string())
+            .spawn(move || {
+                // The output stream isn't Send, so it has to be opened here
+                let (_stream, stream_handle) = match OutputStream::try_default() {
+                    Ok(output) => output,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e.to_string()));
+                        return;
+                    }
+                };
+                if let Err(e) = stream_handle.play_raw(mixer) {
+                    let _ = ready_tx.send(Err(e.to_string()));
+                    return;
+                }
+                let _ = ready_tx.send(Ok(()));
+
+                let _ = shutdown_rx.recv();
+            })?;
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(AudioEngine {
+                commands,
+                retired,
+                status,
+                _shutdown: shutdown,
+            }),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err("audio thread exited before opening the output stream".into()),
+        }
     }
 
-    pub fn change_instrument(&mut self, instrument: InstrumentType) {
-        self.current_instrument = instrument;
-    }
-
-    fn get_note_frequency(key: char) -> f32 {
-        match key.to_lowercase().next().unwrap() {
-            'a' => 440.0,  // A4
-            's' => 493.88, // B4
-            'd' => 523.25, // C4
-            'f' => 587.33, // D4
-            'g' => 659.25, // E4
-            'h' => 698.46, // F4
-            'j' => 783.99, // G4
-            'k' => 880.00, // A5
-            'l' => 987.77, // B5
-            _ => 440.0,    // Default to A4
+    fn send(&mut self, command: AudioCommand) {
+        // Every buffer the mixer retires was displaced by a command, so
+        // freeing them as commands go out keeps up with it
+        while self.retired.pop().is_ok() {}
+        if self.commands.push(command).is_err() {
+            warn!("Audio command queue is full, dropping command");
         }
     }
 
-    pub fn play_note(&self, key: char) {
-        let frequency = Self::get_note_frequency(key);
-        let source = CustomWaveform::new(frequency, self.current_instrument)
-            .take_duration(Duration::from_millis(150))
-            .amplify(0.20);
+    pub fn change_instrument(&mut self, instrument: InstrumentType) {
+        self.send(AudioCommand::SetInstrument(instrument));
+    }
+
+    pub fn set_envelope(&mut self, instrument: InstrumentType, envelope: Envelope) {
+        self.send(AudioCommand::SetEnvelope(instrument, envelope));
+    }
 
-        self.sink.append(source);
+    pub fn set_lofi(&mut self, lofi: bool) {
+        self.send(AudioCommand::SetLofi(lofi));
     }
 
-    pub fn play_chord(&self, keys: &[char]) {
-        for &key in keys {
-            let frequency = Self::get_note_frequency(key);
-            let source = CustomWaveform::new(frequency, self.current_instrument)
-                .take_duration(Duration::from_millis(150))
-                .amplify(0.15); // Slightly lower amplitude for chords
+    pub fn set_kit(&mut self, kit: Option<&DrumKit>) {
+        self.send(AudioCommand::SetKit(DrumKit::sample_data(kit)));
+    }
 
-            self.sink.append(source);
+    pub fn note_on(&mut self, note: u8, velocity: f32) {
+        self.send(AudioCommand::NoteOn(note, velocity));
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.send(AudioCommand::NoteOff(note));
+    }
+
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        for command in settings_commands(settings) {
+            self.send(command);
         }
     }
 
-    pub fn play_recording(&self, recording: &[Note]) {
-        for note in recording {
-            self.play_note(note.key);
-            std::thread::sleep(Duration::from_millis(150)); // Fixed delay between notes
+    pub fn play_recording(&mut self, take: &Take, speed: f32, looping: bool) {
+        self.send(recording_sequence(take, speed, looping));
+    }
+
+    pub fn stop_playback(&mut self) {
+        self.send(AudioCommand::StopSequence);
+    }
+
+    /// Sends the looper's active layers to the mixer, or stops the loop
+    /// station if no loop has been recorded yet.
+    pub fn play_loops(&mut self, looper: &Looper, bpm: f32) {
+        match looper.length_at(bpm) {
+            Some(length) => self.send(AudioCommand::PlayLoops {
+                events: looper.events(bpm),
+                length,
+            }),
+            None => self.stop_loops(),
         }
     }
 
-    pub fn play_drum(&self, drum: DrumSound) {
-        let source = CustomWaveform::new(drum.frequency(), self.current_instrument)
-            .take_duration(Duration::from_millis(drum.duration()))
-            .amplify(0.3);
+    pub fn stop_loops(&mut self) {
+        self.send(AudioCommand::StopLoops);
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.send(AudioCommand::SetTempo(bpm));
+    }
+
+    pub fn set_time_signature(&mut self, time_signature: TimeSignature) {
+        self.send(AudioCommand::SetTimeSignature(time_signature));
+    }
+
+    pub fn set_metronome(&mut self, enabled: bool, volume: f32) {
+        self.send(AudioCommand::SetMetronome { enabled, volume });
+    }
+
+    pub fn count_in(&mut self, until: u64) {
+        self.send(AudioCommand::CountIn(until));
+    }
+
+    /// Sends the sequencer's arrangement to the mixer. Pattern and song
+    /// changes pass `at_bar` so they take over on the next bar line.
+    pub fn set_drums(
+        &mut self,
+        sequencer: &StepSequencer,
+        sounds: &HashMap<char, DrumSound>,
+        at_bar: bool,
+    ) {
+        let (patterns, looping) = sequencer.arrangement(sounds);
+        self.send(AudioCommand::SetDrums {
+            patterns,
+            looping,
+            at_bar,
+        });
+    }
+
+    pub fn transport_play(&mut self) {
+        self.send(AudioCommand::TransportPlay);
+    }
+
+    pub fn transport_pause(&mut self) {
+        self.send(AudioCommand::TransportPause);
+    }
+
+    pub fn transport_stop(&mut self) {
+        self.send(AudioCommand::TransportStop);
+    }
+
+    pub fn set_midi_output(&mut self, output: Option<MidiQueue>) {
+        self.send(AudioCommand::SetMidiOutput(output));
+    }
+
+    pub fn set_midi_only(&mut self, only: bool) {
+        self.send(AudioCommand::SetMidiOnly(only));
+    }
+
+    pub fn set_sync(&mut self, mode: SyncMode) {
+        self.send(AudioCommand::SetSync(mode));
+    }
+
+    pub fn set_sync_input(&mut self, input: Consumer<SyncMessage>) {
+        self.send(AudioCommand::SetSyncInput(input));
+    }
+
+    pub fn playback(&self) -> &PlaybackStatus {
+        &self.status
+    }
 
-        self.sink.append(source);
+    pub fn play_drum(&mut self, drum: DrumSound, velocity: f32) {
+        self.send(AudioCommand::DrumHit(drum, velocity));
     }
 }
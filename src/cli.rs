@@ -0,0 +1,142 @@
+use crate::drums::DrumSound;
+use crate::kit::DrumKit;
+use crate::midi_file;
+use crate::mixer::SAMPLE_RATE;
+use crate::recorder::{Note, Recorder};
+use crate::render;
+use crate::settings::Settings;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "Usage:
+  devtunes                                      Start the TUI
+  devtunes open <project.json>                  Start the TUI with a saved project
+  devtunes import <song.mid> [--track N]        List a MIDI file's tracks, or start
+                                                the TUI with track N as a new take
+  devtunes render <take.json> <out.wav> [--bits 16|24]
+                                                Render a saved take to a WAV file";
+
+pub enum Command {
+    Run,
+    Open(PathBuf),
+    Import {
+        input: PathBuf,
+        /// Counting from 1, as listed; None to list the tracks
+        track: Option<usize>,
+    },
+    Render {
+        input: PathBuf,
+        output: PathBuf,
+        bits: u16,
+    },
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    match args.first().map(String::as_str) {
+        None => Ok(Command::Run),
+        Some("open") => match &args[1..] {
+            [path] => Ok(Command::Open(PathBuf::from(path))),
+            _ => Err(USAGE.to_string()),
+        },
+        Some("import") => match &args[1..] {
+            [input] => Ok(Command::Import {
+                input: PathBuf::from(input),
+                track: None,
+            }),
+            [input, flag, track] if flag == "--track" => match track.parse() {
+                Ok(track) if track > 0 => Ok(Command::Import {
+                    input: PathBuf::from(input),
+                    track: Some(track),
+                }),
+                _ => Err("--track must be a track number from 1".to_string()),
+            },
+            _ => Err(USAGE.to_string()),
+        },
+        Some("render") => {
+            let mut paths = Vec::new();
+            let mut bits = 16;
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                if arg == "--bits" {
+                    bits = match rest.next().map(String::as_str) {
+                        Some("16") => 16,
+                        Some("24") => 24,
+                        _ => return Err("--bits must be 16 or 24".to_string()),
+                    };
+                } else {
+                    paths.push(PathBuf::from(arg));
+                }
+            }
+            match <[PathBuf; 2]>::try_from(paths) {
+                Ok([input, output]) => Ok(Command::Render {
+                    input,
+                    output,
+                    bits,
+                }),
+                Err(_) => Err(USAGE.to_string()),
+            }
+        }
+        Some(_) => Err(USAGE.to_string()),
+    }
+}
+
+pub fn render(input: &Path, output: &Path, bits: u16) -> Result<(), Box<dyn Error>> {
+    let take = Recorder::load_take(input)?;
+    let kit = take.kit.as_ref().and_then(|name| {
+        DrumKit::load_all()
+            .into_iter()
+            .find(|kit| &kit.name == name)
+    });
+    let has_samples = take.notes.iter().any(|note| {
+        matches!(
+            note,
+            Note::Drum {
+                drum: DrumSound::Sample(_),
+                ..
+            }
+        )
+    });
+    if kit.is_none() && has_samples {
+        match &take.kit {
+            Some(name) => eprintln!("Kit {} not found, so sample hits are left out", name),
+            None => eprintln!("The take names no kit, so sample hits are left out"),
+        }
+    }
+    let samples = render::render_recording(
+        &take,
+        render::Backing::none(&take),
+        &Settings::load(),
+        DrumKit::sample_data(kit.as_ref()),
+    );
+    render::write_wav(output, &samples, bits)?;
+    println!(
+        "Rendered {} ({:.1}s) to {}",
+        input.display(),
+        samples.len() as f32 / SAMPLE_RATE as f32,
+        output.display()
+    );
+    Ok(())
+}
+
+/// Prints the tracks of a MIDI file so one can be picked for import.
+pub fn list_tracks(input: &Path) -> Result<(), Box<dyn Error>> {
+    for (i, track) in midi_file::track_list(input)?.iter().enumerate() {
+        let name = if track.name.is_empty() {
+            "(unnamed)"
+        } else {
+            &track.name
+        };
+        println!(
+            "{:>3}  {:<24} {} notes, {} drum hits",
+            i + 1,
+            name,
+            track.notes,
+            track.drums
+        );
+    }
+    println!(
+        "Import one with: devtunes import {} --track N",
+        input.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use crate::drums::DrumSound;
+use std::f32::consts::PI;
+
+// Inharmonic square partials (TR-808 style) for hats and cymbals
+const METAL_FREQUENCIES: [f32; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+
+/// White noise from a xorshift generator, so every hit sounds the same.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// Topology-preserving state variable filter.
+struct Svf {
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    k: f32,
+    ic1: f32,
+    ic2: f32,
+}
+
+impl Svf {
+    fn new(cutoff: f32, q: f32, sample_rate: u32) -> Self {
+        let g = (PI * cutoff / sample_rate as f32).tan();
+        let k = 1.0 / q;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        Self {
+            a1,
+            a2,
+            a3: g * a2,
+            k,
+            ic1: 0.0,
+            ic2: 0.0,
+        }
+    }
+
+    /// Returns the (low, band, high) outputs for one input sample.
+    fn process(&mut self, input: f32) -> (f32, f32, f32) {
+        let v3 = input - self.ic2;
+        let v1 = self.a1 * self.ic1 + self.a2 * v3;
+        let v2 = self.ic2 + self.a2 * self.ic1 + self.a3 * v3;
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+        (v2, v1, input - self.k * v1 - v2)
+    }
+}
+
+/// One synthesized drum hit. Independent of the keyboard instrument.
+pub struct DrumVoice {
+    sound: DrumSound,
+    sample_rate: u32,
+    position: u32,
+    length: u32,
+    phase: f32,
+    metal_phases: [f32; 6],
+    noise: Noise,
+    filter: Svf,
+}
+
+impl DrumVoice {
+    pub fn new(sound: DrumSound, sample_rate: u32) -> Self {
+        let (length, filter) = match sound {
+            DrumSound::Kick => (0.5, Svf::new(200.0, 0.7, sample_rate)),
+            DrumSound::Snare => (0.3, Svf::new(1800.0, 0.7, sample_rate)),
+            DrumSound::HiHat => (0.12, Svf::new(7000.0, 0.7, sample_rate)),
+            DrumSound::Clap => (0.4, Svf::new(1200.0, 1.5, sample_rate)),
+            DrumSound::Cymbal => (1.5, Svf::new(5000.0, 0.7, sample_rate)),
+            // Kit samples are played back by SampleVoice instead
+            DrumSound::Sample(_) => (0.0, Svf::new(1000.0, 0.7, sample_rate)),
+        };
+
+        Self {
+            sound,
+            sample_rate,
+            position: 0,
+            length: (length * sample_rate as f32) as u32,
+            phase: 0.0,
+            metal_phases: [0.0; 6],
+            noise: Noise(0x9E37_79B9),
+            filter,
+        }
+    }
+
+    /// Sine oscillator at a (possibly changing) frequency.
+    fn tone(&mut self, frequency: f32) -> f32 {
+        self.phase = (self.phase + frequency / self.sample_rate as f32).fract();
+        (self.phase * 2.0 * PI).sin()
+    }
+
+    fn metal(&mut self) -> f32 {
+        let sample_rate = self.sample_rate as f32;
+        let mut sum = 0.0;
+        for (phase, frequency) in self.metal_phases.iter_mut().zip(METAL_FREQUENCIES) {
+            *phase = (*phase + frequency / sample_rate).fract();
+            sum += if *phase < 0.5 { 1.0 } else { -1.0 };
+        }
+        sum / METAL_FREQUENCIES.len() as f32
+    }
+
+    fn kick(&mut self, t: f32) -> f32 {
+        // Pitch drops from ~150 Hz to 50 Hz, with a short noise click on top
+        let body = self.tone(50.0 + 100.0 * (-t * 30.0).exp()) * (-t * 8.0).exp();
+        let click = if t < 0.003 {
+            self.noise.next() * (1.0 - t / 0.003)
+        } else {
+            0.0
+        };
+        let (low, _, _) = self.filter.process(body + 0.4 * click);
+        low * 1.2
+    }
+
+    fn snare(&mut self, t: f32) -> f32 {
+        let tone = self.tone(180.0) * (-t * 25.0).exp();
+        let noise = self.noise.next();
+        let (_, _, high) = self.filter.process(noise);
+        0.5 * tone + 0.8 * high * (-t * 18.0).exp()
+    }
+
+    fn clap(&mut self, t: f32) -> f32 {
+        // Three quick bursts followed by a longer tail
+        let envelope = [0.0, 0.01, 0.02]
+            .iter()
+            .filter(|&&start| t >= start)
+            .map(|start| (-(t - start) * 300.0).exp())
+            .fold(0.0f32, f32::max)
+            .max(if t >= 0.03 {
+                0.6 * (-(t - 0.03) * 15.0).exp()
+            } else {
+                0.0
+            });
+        let noise = self.noise.next();
+        let (_, band, _) = self.filter.process(noise);
+        let tone = self.tone(400.0) * (-t * 40.0).exp();
+        1.5 * band * envelope + 0.2 * tone
+    }
+
+    fn cymbal(&mut self, t: f32, decay: f32) -> f32 {
+        let metal = self.metal() + 0.3 * self.noise.next();
+        let (_, _, high) = self.filter.process(metal);
+        high * (-t * decay).exp()
+    }
+}
+
+impl Iterator for DrumVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.length {
+            return None;
+        }
+        let t = self.position as f32 / self.sample_rate as f32;
+        self.position += 1;
+
+        Some(match self.sound {
+            DrumSound::Kick => self.kick(t),
+            DrumSound::Snare => self.snare(t),
+            DrumSound::HiHat => self.cymbal(t, 40.0),
+            DrumSound::Clap => self.clap(t),
+            DrumSound::Cymbal => self.cymbal(t, 3.0),
+            DrumSound::Sample(_) => 0.0,
+        })
+    }
+}
+
+/// Metronome click: a short sine blip, pitched up on the downbeat.
+pub struct ClickVoice {
+    sample_rate: u32,
+    frequency: f32,
+    position: u32,
+    length: u32,
+}
+
+impl ClickVoice {
+    pub fn new(accent: bool, sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            frequency: if accent { 1760.0 } else { 880.0 },
+            position: 0,
+            length: (0.05 * sample_rate as f32) as u32,
+        }
+    }
+}
+
+impl Iterator for ClickVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.length {
+            return None;
+        }
+        let t = self.position as f32 / self.sample_rate as f32;
+        self.position += 1;
+        Some((2.0 * PI * self.frequency * t).sin() * (-t * 80.0).exp())
+    }
+}
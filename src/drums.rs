@@ -1,32 +1,51 @@
+use crate::kit::DrumKit;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Clone, Copy)]
+// The five built-in sounds sit on the bottom row, extra kit samples
+// spill over onto the home row
+const PAD_KEYS: [char; 5] = ['z', 'x', 'c', 'v', 'b'];
+const EXTRA_PAD_KEYS: [char; 9] = ['a', 's', 'd', 'f', 'g', 'h', 'j', 'k', 'l'];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum DrumSound {
     Kick,
     Snare,
     HiHat,
     Clap,
     Cymbal,
+    /// Index into the samples of the selected kit
+    Sample(usize),
 }
 
 impl DrumSound {
-    pub fn frequency(&self) -> f32 {
+    pub const BUILT_IN: [DrumSound; 5] = [
+        DrumSound::Kick,
+        DrumSound::Snare,
+        DrumSound::HiHat,
+        DrumSound::Clap,
+        DrumSound::Cymbal,
+    ];
+
+    pub fn name(&self) -> &'static str {
         match self {
-            DrumSound::Kick => 60.0,    // Low frequency for kick
-            DrumSound::Snare => 200.0,  // Mid frequency for snare
-            DrumSound::HiHat => 1000.0, // High frequency for hi-hat
-            DrumSound::Clap => 300.0,   // Mid-high for clap
-            DrumSound::Cymbal => 800.0, // High for cymbal
+            DrumSound::Kick => "KICK",
+            DrumSound::Snare => "SNARE",
+            DrumSound::HiHat => "HIHAT",
+            DrumSound::Clap => "CLAP",
+            DrumSound::Cymbal => "CYMBAL",
+            DrumSound::Sample(_) => "SAMPLE",
         }
     }
 
-    pub fn duration(&self) -> u64 {
+    pub fn symbol(&self) -> &'static str {
         match self {
-            DrumSound::Kick => 150,
-            DrumSound::Snare => 100,
-            DrumSound::HiHat => 50,
-            DrumSound::Clap => 80,
-            DrumSound::Cymbal => 200,
+            DrumSound::Kick => "💥",
+            DrumSound::Snare => "🥁",
+            DrumSound::HiHat => "🎪",
+            DrumSound::Clap => "👏",
+            DrumSound::Cymbal => "🔊",
+            DrumSound::Sample(_) => "🎵",
         }
     }
 }
@@ -35,22 +54,21 @@ pub struct DrumPad {
     pub sounds: HashMap<char, DrumSound>,
     pub is_drum_mode: bool,
     pub active_beats: Vec<char>,
+    pub kits: Vec<DrumKit>,
+    pub current_kit: Option<usize>,
 }
 
 impl DrumPad {
     pub fn new() -> Self {
-        let mut sounds = HashMap::new();
-        sounds.insert('z', DrumSound::Kick);
-        sounds.insert('x', DrumSound::Snare);
-        sounds.insert('c', DrumSound::HiHat);
-        sounds.insert('v', DrumSound::Clap);
-        sounds.insert('b', DrumSound::Cymbal);
-
-        Self {
-            sounds,
+        let mut pad = Self {
+            sounds: HashMap::new(),
             is_drum_mode: false,
             active_beats: Vec::new(),
-        }
+            kits: DrumKit::load_all(),
+            current_kit: None,
+        };
+        pad.map_sounds();
+        pad
     }
 
     pub fn toggle_mode(&mut self) {
@@ -65,4 +83,75 @@ impl DrumPad {
             None
         }
     }
+
+    pub fn kit(&self) -> Option<&DrumKit> {
+        self.current_kit.and_then(|i| self.kits.get(i))
+    }
+
+    pub fn kit_name(&self) -> &str {
+        self.kit().map_or("Synth", |kit| kit.name.as_str())
+    }
+
+    /// Cycles through the loaded kits and back to the built-in synth.
+    pub fn next_kit(&mut self) -> Option<&DrumKit> {
+        self.current_kit = match self.current_kit {
+            None if !self.kits.is_empty() => Some(0),
+            Some(i) if i + 1 < self.kits.len() => Some(i + 1),
+            _ => None,
+        };
+        self.map_sounds();
+        self.kit()
+    }
+
+    fn map_sounds(&mut self) {
+        self.sounds.clear();
+        for (key, sound) in PAD_KEYS.into_iter().zip(DrumSound::BUILT_IN) {
+            self.sounds.insert(key, sound);
+        }
+
+        let Some(kit) = self.current_kit.and_then(|i| self.kits.get(i)) else {
+            return;
+        };
+
+        // Samples named after a built-in sound replace it on its pad
+        let mut extra_keys = EXTRA_PAD_KEYS.into_iter();
+        for (index, sample) in kit.samples.iter().enumerate() {
+            let pad = DrumSound::BUILT_IN
+                .iter()
+                .position(|sound| sound.name().eq_ignore_ascii_case(&sample.name))
+                .map(|i| PAD_KEYS[i])
+                .or_else(|| extra_keys.next());
+            if let Some(key) = pad {
+                self.sounds.insert(key, DrumSound::Sample(index));
+            }
+        }
+    }
+
+    /// Pad key of a built-in sound, which kit samples of the same name
+    /// take over.
+    pub fn key_for(sound: DrumSound) -> Option<char> {
+        DrumSound::BUILT_IN
+            .iter()
+            .position(|&built_in| built_in == sound)
+            .map(|i| PAD_KEYS[i])
+    }
+
+    /// Pads in display order, with the label to show for each.
+    pub fn pads(&self) -> Vec<(char, String, &'static str)> {
+        PAD_KEYS
+            .iter()
+            .chain(EXTRA_PAD_KEYS.iter())
+            .filter_map(|&key| {
+                let sound = self.sounds.get(&key)?;
+                let label = match sound {
+                    DrumSound::Sample(i) => self
+                        .kit()
+                        .and_then(|kit| kit.samples.get(*i))
+                        .map_or_else(|| sound.name().to_string(), |s| s.name.to_uppercase()),
+                    _ => sound.name().to_string(),
+                };
+                Some((key, label, sound.symbol()))
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+
+const TIME_STEP: f32 = 0.01;
+const MAX_TIME: f32 = 2.0;
+const LEVEL_STEP: f32 = 0.05;
+
+/// Attack/decay/release are in seconds, sustain is a level between 0 and 1.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EnvelopeParam {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl EnvelopeParam {
+    pub const ALL: [EnvelopeParam; 4] = [
+        EnvelopeParam::Attack,
+        EnvelopeParam::Decay,
+        EnvelopeParam::Sustain,
+        EnvelopeParam::Release,
+    ];
+
+    pub fn next(&self) -> Self {
+        match self {
+            EnvelopeParam::Attack => EnvelopeParam::Decay,
+            EnvelopeParam::Decay => EnvelopeParam::Sustain,
+            EnvelopeParam::Sustain => EnvelopeParam::Release,
+            EnvelopeParam::Release => EnvelopeParam::Attack,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            EnvelopeParam::Attack => "A",
+            EnvelopeParam::Decay => "D",
+            EnvelopeParam::Sustain => "S",
+            EnvelopeParam::Release => "R",
+        }
+    }
+}
+
+impl Envelope {
+    pub fn adjust(&mut self, param: EnvelopeParam, steps: i32) {
+        let time = |value: f32| (value + TIME_STEP * steps as f32).clamp(0.0, MAX_TIME);
+        match param {
+            EnvelopeParam::Attack => self.attack = time(self.attack),
+            EnvelopeParam::Decay => self.decay = time(self.decay),
+            EnvelopeParam::Sustain => {
+                self.sustain = (self.sustain + LEVEL_STEP * steps as f32).clamp(0.0, 1.0)
+            }
+            EnvelopeParam::Release => self.release = time(self.release),
+        }
+    }
+
+    pub fn display(&self, param: EnvelopeParam) -> String {
+        match param {
+            EnvelopeParam::Attack => format!("{:.0}ms", self.attack * 1000.0),
+            EnvelopeParam::Decay => format!("{:.0}ms", self.decay * 1000.0),
+            EnvelopeParam::Sustain => format!("{:.0}%", self.sustain * 100.0),
+            EnvelopeParam::Release => format!("{:.0}ms", self.release * 1000.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Shapes the amplitude of a source. The sustain stage is held until
+/// `release` is called, then the source fades out and ends.
+pub struct Adsr<S> {
+    source: S,
+    stage: Stage,
+    attack: u32,
+    decay: u32,
+    sustain: f32,
+    release: u32,
+    level: f32,
+    release_from: f32,
+    position: u32,
+}
+
+impl<S> Adsr<S>
+where
+    S: Iterator<Item = f32>,
+{
+    pub fn new(source: S, envelope: Envelope, sample_rate: u32) -> Self {
+        let samples = |seconds: f32| ((seconds * sample_rate as f32) as u32).max(1);
+        Self {
+            source,
+            stage: Stage::Attack,
+            attack: samples(envelope.attack),
+            decay: samples(envelope.decay),
+            sustain: envelope.sustain,
+            release: samples(envelope.release),
+            level: 0.0,
+            release_from: 0.0,
+            position: 0,
+        }
+    }
+
+    pub fn is_releasing(&self) -> bool {
+        self.stage == Stage::Release
+    }
+
+    pub fn release(&mut self) {
+        if self.stage != Stage::Release {
+            self.stage = Stage::Release;
+            self.release_from = self.level;
+            self.position = 0;
+        }
+    }
+
+    fn advance(&mut self, length: u32, next: Stage) -> f32 {
+        let progress = self.position as f32 / length as f32;
+        self.position += 1;
+        if self.position >= length {
+            self.stage = next;
+            self.position = 0;
+        }
+        progress
+    }
+}
+
+impl<S> Iterator for Adsr<S>
+where
+    S: Iterator<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.level = match self.stage {
+            Stage::Attack => self.advance(self.attack, Stage::Decay),
+            Stage::Decay => {
+                let progress = self.advance(self.decay, Stage::Sustain);
+                1.0 - (1.0 - self.sustain) * progress
+            }
+            Stage::Sustain => self.sustain,
+            Stage::Release => {
+                if self.position >= self.release {
+                    return None;
+                }
+                let progress = self.position as f32 / self.release as f32;
+                self.position += 1;
+                self.release_from * (1.0 - progress)
+            }
+        };
+
+        self.source.next().map(|sample| sample * self.level)
+    }
+}
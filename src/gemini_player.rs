@@ -1,17 +1,19 @@
+use crate::keyboard;
 use google_generative_ai_rs::v1::api::{Client, PostResult};
 use google_generative_ai_rs::v1::gemini::{
     request::{GenerationConfig, Request, SafetySettings, Tools},
     Model, ResponseType,
 };
 use google_generative_ai_rs::v1::gemini::{Content, Part, Role};
-use log::warn;
-use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::time::{Duration, Instant};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct MelodyPattern {
+// Gemini's millisecond durations are taken to be at this tempo
+const PATTERN_BPM: f32 = 120.0;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MelodyPattern {
     notes: Vec<char>,
     durations: Vec<u64>,
     mood: String,
@@ -66,20 +68,16 @@ impl GeminiPlayer {
     }
 
     pub async fn generate_melody(&mut self, mood: &str) -> Result<(), Box<dyn Error>> {
+        let mapping = keyboard::LAYOUT
+            .iter()
+            .map(|&(key, note)| format!("'{}' => {}", key, keyboard::note_name(note)))
+            .collect::<Vec<_>>()
+            .join(", ");
         let prompt = format!(
             r#"Create a unique musical melody pattern that expresses a {} mood.
                         Generate random notes and durations within these constraints:
                         - notes: use only these letters in any order: a,s,d,f,g,h,j,k,l
-                        - notes are mapped like this:
-                        'a' => 440.0,  // A4
-                        's' => 493.88, // B4
-                        'd' => 523.25, // C4
-                        'f' => 587.33, // D4
-                        'g' => 659.25, // E4
-                        'h' => 698.46, // F4
-                        'j' => 783.99, // G4
-                        'k' => 880.00, // A5
-                        'l' => 987.77, // B5
+                        - notes are mapped like this: {}
                         - durations: use random numbers(integers) to make the melody better between 100 and 800
                         - create exactly 9 notes with corresponding durations which really match the mood as a melody.
                         Return only a JSON object with this structure:
@@ -88,7 +86,7 @@ impl GeminiPlayer {
                             "durations": [array of 9 random durations],
                             "mood": "{}"
                         }}"#,
-            mood, mood
+            mood, mapping, mood
         );
 
         let content = Content {
@@ -120,8 +118,8 @@ impl GeminiPlayer {
 
         match response {
             PostResult::Rest(gemini_response) => {
-                if let Some(candidate) = gemini_response.candidates.get(0) {
-                    if let Some(part) = candidate.content.parts.get(0) {
+                if let Some(candidate) = gemini_response.candidates.first() {
+                    if let Some(part) = candidate.content.parts.first() {
                         if let Some(text) = &part.text {
                             if let Some(json_text) = Self::extract_json(text) {
                                 match serde_json::from_str::<MelodyPattern>(&json_text) {
@@ -134,7 +132,7 @@ impl GeminiPlayer {
                                             && pattern
                                                 .durations
                                                 .iter()
-                                                .all(|&d| d >= 200 && d <= 800)
+                                                .all(|d| (200..=800).contains(d))
                                         {
                                             self.current_pattern = Some(pattern);
                                             self.pattern_index = 0;
@@ -160,13 +158,22 @@ impl GeminiPlayer {
         }
     }
 
-    pub fn has_pattern(&self) -> bool {
-        self.current_pattern.is_some()
+    pub fn pattern(&self) -> Option<&MelodyPattern> {
+        self.current_pattern.as_ref()
+    }
+
+    /// Picks up a saved melody from the top.
+    pub fn set_pattern(&mut self, pattern: MelodyPattern) {
+        self.current_pattern = Some(pattern);
+        self.pattern_index = 0;
     }
 
-    pub fn get_next_note(&mut self) -> Option<(char, Duration)> {
+    /// Returns the next note once the previous one has had its time, with
+    /// durations stretched to the transport's `bpm`.
+    pub fn get_next_note(&mut self, bpm: f32) -> Option<(char, Duration)> {
         if let Some(pattern) = &self.current_pattern {
             let now = Instant::now();
+            let stretch = PATTERN_BPM / bpm;
 
             if self.pattern_index >= pattern.notes.len() {
                 self.pattern_index = 0;
@@ -176,13 +183,14 @@ impl GeminiPlayer {
             if self.pattern_index == 0
                 || now.duration_since(self.last_note_time)
                     >= Duration::from_millis(pattern.durations[self.pattern_index - 1])
+                        .mul_f32(stretch)
             {
                 let note = pattern.notes[self.pattern_index];
                 let duration = pattern.durations[self.pattern_index];
                 self.pattern_index += 1;
                 self.last_note_time = now;
 
-                Some((note, Duration::from_millis(duration)))
+                Some((note, Duration::from_millis(duration).mul_f32(stretch)))
             } else {
                 None
             }
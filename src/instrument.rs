@@ -1,59 +1,123 @@
 use rodio::Source;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
 use std::time::Duration;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum InstrumentType {
+    #[default]
     Sine,
     Square,
     Triangle,
     Saw,
 }
 
+impl InstrumentType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstrumentType::Sine => "Sine",
+            InstrumentType::Square => "Square",
+            InstrumentType::Triangle => "Triangle",
+            InstrumentType::Saw => "Saw",
+        }
+    }
+}
+
 pub struct CustomWaveform {
     frequency: f32,
-    num_samples: usize,
     instrument: InstrumentType,
     sample_rate: u32,
-    position: usize,
+    phase: f32,
+    lofi: bool,
 }
 
 impl CustomWaveform {
     pub fn new(frequency: f32, instrument: InstrumentType) -> Self {
         Self {
             frequency,
-            num_samples: 0,
             instrument,
             sample_rate: 44100,
-            position: 0,
+            phase: 0.0,
+            lofi: false,
         }
     }
 
-    fn get_sample(&self, position: usize) -> f32 {
-        let t = position as f32 / self.sample_rate as f32;
-        let x = t * self.frequency * 2.0 * PI;
+    /// Switches to the naive, aliasing waveforms for a grittier sound.
+    pub fn with_lofi(mut self, lofi: bool) -> Self {
+        self.lofi = lofi;
+        self
+    }
 
+    fn naive_sample(&self, t: f32) -> f32 {
         match self.instrument {
-            InstrumentType::Sine => x.sin(),
+            InstrumentType::Sine => (t * 2.0 * PI).sin(),
             InstrumentType::Square => {
-                if x.sin() >= 0.0 {
+                if t < 0.5 {
                     0.5
                 } else {
                     -0.5
                 }
             }
-            InstrumentType::Triangle => (2.0 * (x / (2.0 * PI)).fract() - 1.0).abs() * 2.0 - 1.0,
-            InstrumentType::Saw => (x / (2.0 * PI)).fract() * 2.0 - 1.0,
+            InstrumentType::Triangle => (2.0 * t - 1.0).abs() * 2.0 - 1.0,
+            InstrumentType::Saw => t * 2.0 - 1.0,
+        }
+    }
+
+    // PolyBLEP/PolyBLAMP: smooth the discontinuities of the naive waves
+    // with a polynomial residual so harmonics above Nyquist don't fold back
+    fn band_limited_sample(&self, t: f32, dt: f32) -> f32 {
+        let naive = self.naive_sample(t);
+        match self.instrument {
+            InstrumentType::Sine => naive,
+            InstrumentType::Square => {
+                naive + 0.5 * (poly_blep(t, dt) - poly_blep((t + 0.5).fract(), dt))
+            }
+            // The slope turns by 8 per cycle at each corner, and the
+            // PolyBLAMP residual is for a turn of 2 per sample
+            InstrumentType::Triangle => {
+                naive + 4.0 * dt * (poly_blamp((t + 0.5).fract(), dt) - poly_blamp(t, dt))
+            }
+            InstrumentType::Saw => naive - poly_blep(t, dt),
         }
     }
 }
 
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt - 1.0;
+        -t * t * t / 3.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
+
 impl Iterator for CustomWaveform {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
-        self.position = self.position.wrapping_add(1);
-        Some(self.get_sample(self.position))
+        let dt = self.frequency / self.sample_rate as f32;
+        self.phase = (self.phase + dt).fract();
+
+        Some(if self.lofi {
+            self.naive_sample(self.phase)
+        } else {
+            self.band_limited_sample(self.phase, dt)
+        })
     }
 }
 
@@ -74,3 +138,76 @@ impl Source for CustomWaveform {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // B5, the top key of the keyboard
+    const FREQUENCY: f32 = 987.77;
+    const LENGTH: usize = 4096;
+
+    /// Share of a waveform's energy that lands away from its harmonics,
+    /// in dB. Aliased harmonics fold back in between the real ones.
+    fn alias_level(instrument: InstrumentType, lofi: bool) -> f64 {
+        let samples = CustomWaveform::new(FREQUENCY, instrument)
+            .with_lofi(lofi)
+            .take(LENGTH)
+            .enumerate()
+            .map(|(i, sample)| {
+                // Blackman-Harris window, so the harmonics don't smear
+                // across the spectrum
+                let x = 2.0 * std::f64::consts::PI * i as f64 / LENGTH as f64;
+                let window = 0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos()
+                    - 0.01168 * (3.0 * x).cos();
+                sample as f64 * window
+            })
+            .collect::<Vec<_>>();
+
+        let bin_width = 44100.0 / LENGTH as f64;
+        let harmonic = FREQUENCY as f64 / bin_width;
+        let (mut total, mut aliased) = (0.0, 0.0);
+        for bin in 1..LENGTH / 2 {
+            let step = 2.0 * std::f64::consts::PI * bin as f64 / LENGTH as f64;
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, &sample) in samples.iter().enumerate() {
+                re += sample * (step * i as f64).cos();
+                im -= sample * (step * i as f64).sin();
+            }
+            let energy = re * re + im * im;
+            total += energy;
+            let offset = bin as f64 / harmonic;
+            if (offset - offset.round()).abs() * harmonic > 6.0 {
+                aliased += energy;
+            }
+        }
+        10.0 * (aliased / total).log10()
+    }
+
+    #[test]
+    fn band_limited_waves_alias_less_than_naive_ones() {
+        // The lo-fi waves have to land above the threshold for the band
+        // limited ones landing below it to mean anything. The triangle's
+        // harmonics fall off faster, so its bar is much lower.
+        for (instrument, threshold) in [
+            (InstrumentType::Square, -25.0),
+            (InstrumentType::Saw, -25.0),
+            (InstrumentType::Triangle, -55.0),
+        ] {
+            let band_limited = alias_level(instrument, false);
+            let lofi = alias_level(instrument, true);
+            assert!(
+                band_limited < threshold,
+                "{:?} band limited aliasing at {:.1} dB",
+                instrument,
+                band_limited
+            );
+            assert!(
+                lofi > threshold,
+                "{:?} lo-fi aliasing at {:.1} dB",
+                instrument,
+                lofi
+            );
+        }
+    }
+}
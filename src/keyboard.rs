@@ -1,88 +1,180 @@
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
-#[derive(Clone)] // Add this line
+/// Keys from left to right with the MIDI note each plays unshifted, A4 to B5
+pub const LAYOUT: [(char, u8); 9] = [
+    ('a', 69),
+    ('s', 71),
+    ('d', 72),
+    ('f', 74),
+    ('g', 76),
+    ('h', 77),
+    ('j', 79),
+    ('k', 81),
+    ('l', 83),
+];
+
+pub const MIN_OCTAVE: i32 = -3;
+pub const MAX_OCTAVE: i32 = 3;
+/// Transpose runs up to this many semitones either way
+pub const MAX_TRANSPOSE: i32 = 11;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Equal-tempered frequency of a MIDI note, with A4 (69) at 440 Hz.
+pub fn note_frequency(note: u8) -> f32 {
+    440.0 * 2f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+/// Name of a MIDI note with its octave, such as "C5" for 72.
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// MIDI note `key` plays `shift` semitones up, if it's a keyboard key.
+pub fn key_note(key: char, shift: i32) -> Option<u8> {
+    LAYOUT
+        .iter()
+        .find(|&&(k, _)| k == key)
+        .map(|&(_, note)| (note as i32 + shift).clamp(0, 127) as u8)
+}
+
+#[derive(Clone)]
 pub struct Key {
-    pub note: String,
-    pub frequency: f32,
+    /// Note name at the current octave and transpose, for the label
+    pub name: String,
+    /// MIDI note the key plays at the current octave and transpose
+    pub note: u8,
     pub is_pressed: bool,
     pub press_time: Option<Instant>,
+    // Note the last press started, so a release ends it even if the
+    // octave has changed since
+    sounding: u8,
 }
 
 pub struct Keyboard {
     pub keys: HashMap<char, Key>,
     pub active_keys: Vec<char>,
+    pub octave: i32,
+    /// Semitones on top of the octave
+    pub transpose: i32,
 }
 
 impl Keyboard {
     pub fn new() -> Self {
-        let mut keys = HashMap::new();
-
-        // Define keys with their notes and frequencies
-        let key_mappings = vec![
-            ('a', "A4", 440.0),
-            ('s', "B4", 493.88),
-            ('d', "C4", 523.25),
-            ('f', "D4", 587.33),
-            ('g', "E4", 659.25),
-            ('h', "F4", 698.46),
-            ('j', "G4", 783.99),
-            ('k', "A5", 880.00),
-            ('l', "B5", 987.77),
-        ];
-
-        for (key, note, freq) in key_mappings {
-            keys.insert(
-                key,
-                Key {
-                    note: note.to_string(),
-                    frequency: freq,
-                    is_pressed: false,
-                    press_time: None,
-                },
-            );
-        }
+        let keys = LAYOUT
+            .iter()
+            .map(|&(key, note)| {
+                (
+                    key,
+                    Key {
+                        name: note_name(note),
+                        note,
+                        is_pressed: false,
+                        press_time: None,
+                        sounding: note,
+                    },
+                )
+            })
+            .collect();
 
         Keyboard {
             keys,
             active_keys: Vec::new(),
+            octave: 0,
+            transpose: 0,
+        }
+    }
+
+    /// Semitones every key is shifted by.
+    pub fn shift(&self) -> i32 {
+        self.octave * 12 + self.transpose
+    }
+
+    /// Moves the keyboard to a new octave and transpose, relabelling the
+    /// keys. Notes already sounding keep their pitch.
+    pub fn set_shift(&mut self, octave: i32, transpose: i32) {
+        self.octave = octave.clamp(MIN_OCTAVE, MAX_OCTAVE);
+        self.transpose = transpose.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+        let shift = self.shift();
+        for (&key, info) in self.keys.iter_mut() {
+            if let Some(note) = key_note(key, shift) {
+                info.note = note;
+                info.name = note_name(note);
+            }
         }
     }
 
-    pub fn press_key(&mut self, key: char) {
+    /// The note a key plays right now.
+    pub fn note(&self, key: char) -> Option<u8> {
+        self.keys.get(&key).map(|info| info.note)
+    }
+
+    /// Presses a key that sounds `note`, released after a short tap.
+    pub fn press_key(&mut self, key: char, note: u8) {
         if let Some(k) = self.keys.get_mut(&key) {
             k.is_pressed = true;
             k.press_time = Some(Instant::now());
+            k.sounding = note;
             if !self.active_keys.contains(&key) {
                 self.active_keys.push(key);
             }
         }
     }
 
-    pub fn update(&mut self) {
-        let now = Instant::now();
-        self.keys.iter_mut().for_each(|(_, key)| {
-            if let Some(press_time) = key.press_time {
-                if now.duration_since(press_time) > Duration::from_millis(150) {
-                    key.is_pressed = false;
-                    key.press_time = None;
-                }
+    /// Presses a key that stays down until `release_key`, for terminals
+    /// that report key releases.
+    pub fn hold_key(&mut self, key: char, note: u8) {
+        if let Some(k) = self.keys.get_mut(&key) {
+            k.is_pressed = true;
+            k.press_time = None;
+            k.sounding = note;
+            if !self.active_keys.contains(&key) {
+                self.active_keys.push(key);
             }
-        });
-        self.active_keys.retain(|&k| self.keys[&k].is_pressed);
+        }
+    }
+
+    /// Lets go of a key, returning the note it was sounding.
+    pub fn release_key(&mut self, key: char) -> Option<u8> {
+        match self.keys.get_mut(&key) {
+            Some(k) if k.is_pressed => {
+                k.is_pressed = false;
+                k.press_time = None;
+                let note = k.sounding;
+                self.active_keys.retain(|&k| k != key);
+                Some(note)
+            }
+            _ => None,
+        }
+    }
+
+    /// The note a key is sounding while it's down.
+    pub fn sounding(&self, key: char) -> Option<u8> {
+        self.keys
+            .get(&key)
+            .filter(|k| k.is_pressed)
+            .map(|k| k.sounding)
     }
 
-    pub fn release_keys(&mut self) {
-        for key in &mut self.keys.values_mut() {
+    /// Releases keys pressed with `press_key` once they've sounded for
+    /// 150 ms, returning the notes they were sounding.
+    pub fn release_keys(&mut self) -> Vec<u8> {
+        let mut released = Vec::new();
+        for key in self.keys.values_mut() {
             if key.is_pressed {
                 if let Some(press_time) = key.press_time {
                     if press_time.elapsed() > Duration::from_millis(150) {
                         key.is_pressed = false;
                         key.press_time = None;
+                        released.push(key.sounding);
                     }
                 }
             }
         }
         self.active_keys.retain(|&k| self.keys[&k].is_pressed);
+        released
     }
 }
@@ -0,0 +1,138 @@
+use crate::drums::DrumSound;
+use crate::midi_file;
+use crate::mixer::SAMPLE_RATE;
+use log::warn;
+use rodio::source::UniformSourceIterator;
+use rodio::{Decoder, Source};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A folder of WAV samples, decoded to mono at the mixer's sample rate.
+pub struct DrumKit {
+    pub name: String,
+    pub samples: Vec<KitSample>,
+}
+
+/// Kit samples the way the mixer takes them: each one's data, and the
+/// General MIDI note it goes out as
+pub type KitSounds = Vec<(Arc<[f32]>, u8)>;
+
+pub struct KitSample {
+    pub name: String,
+    pub data: Arc<[f32]>,
+}
+
+impl DrumKit {
+    /// Kit folders are looked up in `./kits` and in the devtunes data dir.
+    pub fn search_dirs() -> Vec<PathBuf> {
+        let mut dirs = vec![PathBuf::from("kits")];
+        if let Some(data_dir) = dirs::data_dir() {
+            dirs.push(data_dir.join("devtunes").join("kits"));
+        }
+        dirs
+    }
+
+    pub fn load_all() -> Vec<DrumKit> {
+        let mut kits = Vec::new();
+        for dir in Self::search_dirs() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut folders = entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>();
+            folders.sort();
+
+            for folder in folders {
+                match Self::load(&folder) {
+                    Ok(kit) if !kit.samples.is_empty() => kits.push(kit),
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to load kit {}: {}", folder.display(), e),
+                }
+            }
+        }
+        kits
+    }
+
+    /// Notes are worked out the same way as for MIDI export, so a pad
+    /// sends the same note live as it does in an exported file.
+    pub fn sample_data(kit: Option<&DrumKit>) -> KitSounds {
+        kit.map(|kit| {
+            kit.samples
+                .iter()
+                .enumerate()
+                .map(|(index, sample)| {
+                    let note = midi_file::drum_note(DrumSound::Sample(index), Some(kit));
+                    (Arc::clone(&sample.data), note)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn load(folder: &Path) -> Result<DrumKit, Box<dyn Error>> {
+        let mut files = fs::read_dir(folder)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut samples = Vec::new();
+        for file in files {
+            match decode(&file) {
+                Ok(data) => samples.push(KitSample {
+                    name: file_stem(&file),
+                    data,
+                }),
+                Err(e) => warn!("Skipping sample {}: {}", file.display(), e),
+            }
+        }
+
+        Ok(DrumKit {
+            name: file_stem(folder),
+            samples,
+        })
+    }
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn decode(path: &Path) -> Result<Arc<[f32]>, Box<dyn Error>> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let samples: Vec<f32> =
+        UniformSourceIterator::new(decoder.convert_samples::<f32>(), 1, SAMPLE_RATE).collect();
+    Ok(samples.into())
+}
+
+/// Plays a decoded sample once from start to finish.
+pub struct SampleVoice {
+    data: Arc<[f32]>,
+    position: usize,
+}
+
+impl SampleVoice {
+    pub fn new(data: Arc<[f32]>) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl Iterator for SampleVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.data.get(self.position).copied();
+        self.position += 1;
+        sample
+    }
+}
@@ -1,9 +1,15 @@
+use crate::audio::{SequenceEvent, SequenceSound};
+use crate::instrument::InstrumentType;
+use crate::recorder::{Note, TAP_LENGTH};
+use crate::transport::DEFAULT_BPM;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Loop {
-    pub notes: Vec<(char, Duration)>,
-    pub instrument: String,
+    pub notes: Vec<Note>,
+    pub instrument: InstrumentType,
     pub is_active: bool,
 }
 
@@ -13,6 +19,14 @@ pub struct Looper {
     pub current_loop: usize,
     start_time: Option<Instant>,
     pub max_loops: usize,
+    /// Locked by the first loop so every layer repeats in sync
+    pub length: Option<Duration>,
+    /// Tempo the loops were recorded at; they follow the transport from there
+    pub bpm: f32,
+    // Loop time per second of wall-clock time while recording
+    time_scale: f32,
+    // Index and press time of the note each sounding MIDI note started
+    held: HashMap<u8, (usize, Instant)>,
 }
 
 impl Looper {
@@ -23,52 +37,211 @@ impl Looper {
             current_loop: 0,
             start_time: None,
             max_loops: 4, // Maximum 4 loops
+            length: None,
+            bpm: DEFAULT_BPM,
+            time_scale: 1.0,
+            held: HashMap::new(),
         }
     }
 
-    pub fn start_recording(&mut self) {
+    pub fn start_recording(&mut self, instrument: InstrumentType, bpm: f32) {
+        if self.length.is_none() {
+            self.bpm = bpm;
+        }
+        self.time_scale = bpm / self.bpm;
         self.is_recording = true;
         self.start_time = Some(Instant::now());
+        self.held.clear();
 
-        // Create new loop if needed
-        if self.current_loop >= self.loops.len() {
-            self.loops.push(Loop {
+        // Create new loop if needed; a cleared slot is recorded into again
+        match self.loops.get_mut(self.current_loop) {
+            Some(loop_track) if loop_track.notes.is_empty() => loop_track.is_active = true,
+            Some(_) => {}
+            None => self.loops.push(Loop {
                 notes: Vec::new(),
-                instrument: String::from("Default"),
+                instrument,
                 is_active: true,
-            });
+            }),
         }
     }
 
-    pub fn stop_recording(&mut self) {
+    /// Closes the loop being recorded. The first loop is rounded to whole
+    /// bars of `bar` and sets the length every later layer shares.
+    pub fn stop_recording(&mut self, bar: Duration) {
+        let Some(start) = self.start_time.take() else {
+            return;
+        };
         self.is_recording = false;
-        self.start_time = None;
-        if self.current_loop < self.max_loops - 1 {
-            self.current_loop += 1;
+
+        if self.length.is_none() {
+            // An empty first loop doesn't get to set the length
+            if self.loops.last().is_some_and(|l| l.notes.is_empty()) {
+                self.loops.pop();
+                return;
+            }
+            let bars = (start.elapsed().as_secs_f32() / bar.as_secs_f32()).round();
+            let length = bar.mul_f32(bars.max(1.0));
+            self.length = Some(length);
+            if let Some(first) = self.loops.last_mut() {
+                for note in &mut first.notes {
+                    note.set_timestamp(wrap(note.timestamp(), length));
+                }
+            }
+        }
+
+        // Notes still held when recording stops end with the loop
+        let length = self.length.unwrap_or_default();
+        for (_, (index, pressed)) in self.held.drain() {
+            if let Some(note) = self
+                .loops
+                .get_mut(self.current_loop)
+                .and_then(|l| l.notes.get_mut(index))
+            {
+                note.set_duration(pressed.elapsed().mul_f32(self.time_scale).min(length));
+            }
         }
+
+        self.current_loop = self.next_free();
+    }
+
+    /// Slot the next recording goes in: the first empty one, or the last
+    /// once they're all taken.
+    fn next_free(&self) -> usize {
+        self.loops
+            .iter()
+            .position(|l| l.notes.is_empty())
+            .unwrap_or(self.loops.len())
+            .min(self.max_loops - 1)
     }
 
-    pub fn record_note(&mut self, key: char, instrument: &str) {
-        if let Some(start) = self.start_time {
-            if let Some(loop_track) = self.loops.get_mut(self.current_loop) {
-                loop_track.instrument = instrument.to_string();
-                loop_track.notes.push((key, start.elapsed()));
+    /// Records a note into the loop being recorded. Overdubs are placed at
+    /// `loop_position`, the playhead of the loops that are already playing.
+    pub fn record_note(
+        &mut self,
+        key: char,
+        shift: i8,
+        instrument: InstrumentType,
+        loop_position: Option<Duration>,
+    ) {
+        let Some(start) = self.start_time else {
+            return;
+        };
+        let elapsed = loop_position.unwrap_or_else(|| start.elapsed());
+        let timestamp = match self.length {
+            Some(length) => wrap(elapsed.mul_f32(self.time_scale), length),
+            None => elapsed,
+        };
+
+        if let Some(loop_track) = self.loops.get_mut(self.current_loop) {
+            loop_track.instrument = instrument;
+            let note = Note::Key {
+                key,
+                timestamp,
+                duration: TAP_LENGTH,
+                instrument: None,
+                shift,
+            };
+            if let Some(pitch) = note.pitch() {
+                self.held
+                    .insert(pitch, (loop_track.notes.len(), Instant::now()));
             }
+            loop_track.notes.push(note);
+        }
+    }
+
+    /// Ends the held note sounding MIDI note `pitch`.
+    pub fn record_release(&mut self, pitch: u8) {
+        let Some((index, pressed)) = self.held.remove(&pitch) else {
+            return;
+        };
+        let held = pressed.elapsed().mul_f32(self.time_scale);
+        let duration = match self.length {
+            Some(length) => held.min(length),
+            None => held,
+        };
+        if let Some(note) = self
+            .loops
+            .get_mut(self.current_loop)
+            .and_then(|l| l.notes.get_mut(index))
+        {
+            note.set_duration(duration);
         }
     }
 
+    /// Loop length once stretched to `bpm`.
+    pub fn length_at(&self, bpm: f32) -> Option<Duration> {
+        self.length.map(|length| length.mul_f32(self.bpm / bpm))
+    }
+
+    /// Every note of the active loops at `bpm`, each with its loop's
+    /// instrument.
+    pub fn events(&self, bpm: f32) -> Vec<SequenceEvent> {
+        let stretch = self.bpm / bpm;
+        let mut events = self
+            .loops
+            .iter()
+            .filter(|l| l.is_active)
+            .flat_map(|l| {
+                // Loops only ever record keyboard notes
+                l.notes.iter().filter_map(move |note| match *note {
+                    Note::Key {
+                        timestamp,
+                        duration,
+                        ..
+                    } => Some(SequenceEvent {
+                        at: timestamp.mul_f32(stretch),
+                        sound: SequenceSound::Note {
+                            note: note.pitch()?,
+                            duration: duration.mul_f32(stretch),
+                            instrument: Some(l.instrument),
+                        },
+                    }),
+                    _ => None,
+                })
+            })
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.at);
+        events
+    }
+
+    /// Replaces every loop, as when opening a project.
+    pub fn restore(&mut self, loops: Vec<Loop>, length: Option<Duration>, bpm: f32) {
+        self.is_recording = false;
+        self.start_time = None;
+        self.held.clear();
+        self.loops = loops;
+        self.loops.truncate(self.max_loops);
+        self.current_loop = self.next_free();
+        self.length = length.filter(|_| !self.loops.is_empty());
+        self.bpm = bpm;
+    }
+
     pub fn toggle_loop(&mut self, index: usize) {
         if let Some(loop_track) = self.loops.get_mut(index) {
             loop_track.is_active = !loop_track.is_active;
         }
     }
 
+    /// Empties a loop, leaving the others in the slots their keys pick.
     pub fn clear_loop(&mut self, index: usize) {
-        if index < self.loops.len() {
-            self.loops.remove(index);
-            if self.current_loop >= self.loops.len() {
-                self.current_loop = self.loops.len().saturating_sub(1);
+        if let Some(loop_track) = self.loops.get_mut(index) {
+            loop_track.notes.clear();
+            // Empty slots at the end are as good as none
+            while self.loops.last().is_some_and(|l| l.notes.is_empty()) {
+                self.loops.pop();
+            }
+            // Record into the first free slot next
+            self.current_loop = self.next_free();
+            if self.loops.is_empty() {
+                self.length = None;
             }
         }
     }
 }
+
+fn wrap(time: Duration, length: Duration) -> Duration {
+    if length.is_zero() {
+        return time;
+    }
+    Duration::from_nanos((time.as_nanos() % length.as_nanos()) as u64)
+}
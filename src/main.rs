@@ -1,50 +1,384 @@
 mod audio;
+mod cli;
+mod drum_synth;
 mod drums;
+mod envelope;
 mod gemini_player;
 mod instrument;
 mod keyboard;
+mod kit;
+mod looper;
+mod midi_file;
+mod midi_input;
+mod midi_output;
+mod mixer;
+mod project;
+mod quantize;
 mod recorder;
+mod render;
+mod rhythm;
+mod sequencer;
+mod settings;
+mod sync;
+mod transport;
 mod ui;
 
-use crossterm::event::{self, Event, KeyCode};
-use ratatui::style::{Color, Modifier, Style};
+use cli::Command;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use drums::{DrumPad, DrumSound};
+use instrument::InstrumentType;
+use log::warn;
+use midi_input::MidiEvent;
 use std::error::Error;
 use std::time::{Duration, Instant};
+use transport::TransportState;
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    };
+    let mut import = None;
+    let project_path = match command {
+        Command::Run => None,
+        Command::Open(path) => Some(path),
+        Command::Import { input, track: None } => return cli::list_tracks(&input),
+        Command::Import {
+            input,
+            track: Some(track),
+        } => {
+            // Read before the TUI starts, so errors land on the console
+            import = Some(midi_file::import(&input, track - 1)?);
+            None
+        }
+        Command::Render {
+            input,
+            output,
+            bits,
+        } => return cli::render(&input, &output, bits),
+    };
+
+    // Leave the terminal usable before the panic message is printed
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = ui::restore_terminal(true);
+        default_hook(info);
+    }));
+
     let mut app = ui::App::new()?;
     let mut audio = audio::AudioEngine::new()?;
-    let mut is_playing = false;
+    audio.apply_settings(&app.settings);
+    if let Some(path) = project_path {
+        app.project_path = path;
+        // A path that doesn't exist yet is where the new project gets saved
+        if app.project_path.exists() {
+            open_project(&mut app, &mut audio);
+        }
+    } else if let Some(take) = import {
+        app.recorder.add_take(take);
+        app.notify(
+            "Imported the MIDI track; press P to play it".to_string(),
+            true,
+        );
+    } else {
+        app.pending_restore = project::load_autosave();
+    }
+    let mut last_autosave = Instant::now();
+
+    // Controllers are optional; without a MIDI system the keyboard still works
+    let (clock_input, clock_queue) = sync::queue();
+    audio.set_sync_input(clock_queue);
+    let midi = match midi_input::MidiInputs::open(clock_input) {
+        Ok(midi) => Some(midi),
+        Err(e) => {
+            warn!("MIDI input unavailable: {}", e);
+            None
+        }
+    };
+    app.midi_inputs = midi
+        .as_ref()
+        .map_or_else(Vec::new, |midi| midi.ports.clone());
+    let mut midi_out = None;
+    if let Some(port) = app.settings.midi_output.clone() {
+        connect_midi_output(&mut app, &mut audio, &mut midi_out, Some(port));
+    }
 
     loop {
         // Release any keys that have been pressed long enough
-        app.keyboard.release_keys();
+        for note in app.keyboard.release_keys() {
+            release_note(&mut app, &mut audio, note);
+        }
+        if let Some(midi) = &midi {
+            for event in midi.poll() {
+                // Controllers wait on the restore prompt like the keyboard
+                if app.pending_restore.is_none() {
+                    handle_midi(&mut app, &mut audio, event);
+                }
+            }
+        }
+        app.set_playhead(audio.playback());
+        // Following MIDI clock, the tempo is whatever the source says
+        if let Some(bpm) = app.sync_bpm {
+            if (bpm - app.transport.bpm).abs() >= 0.1 {
+                app.transport.bpm = bpm;
+                if app.loop_station {
+                    audio.play_loops(&app.looper, app.transport.bpm);
+                }
+            }
+        }
+
+        // Holding off until the restore prompt is answered keeps the last
+        // session's autosave intact
+        if app.pending_restore.is_none() && last_autosave.elapsed() >= AUTOSAVE_INTERVAL {
+            if let Err(e) = project::autosave(&app.project()) {
+                warn!("Autosave failed: {}", e);
+            }
+            last_autosave = Instant::now();
+        }
 
         app.draw()?;
 
         if event::poll(Duration::from_millis(16))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
+            match event::read()? {
+                // Held keys auto-repeat. Only tempo nudges and cursor moves
+                // should run again: a held note is already sounding, and
+                // every other key toggles something or writes a file
+                Event::Key(key) if key.kind == KeyEventKind::Repeat && !repeats(key.code) => {}
+                Event::Key(key) if key.kind == KeyEventKind::Release => {
+                    if let KeyCode::Char(c) = key.code {
+                        if let Some(note) = app.keyboard.release_key(c) {
+                            release_note(&mut app, &mut audio, note);
+                        }
+                    }
+                }
+                Event::Key(key) => match key.code {
+                    KeyCode::Char('y' | 'Y') if app.pending_restore.is_some() => {
+                        if let Some(project) = app.pending_restore.take() {
+                            apply_project(&mut app, &mut audio, project);
+                            app.notify("Restored the last session".to_string(), true);
+                        }
+                    }
+                    KeyCode::Char('n' | 'N') | KeyCode::Esc if app.pending_restore.is_some() => {
+                        app.pending_restore = None;
+                    }
+                    // Nothing else can change the session until the prompt is
+                    // answered, so there's nothing to autosave meanwhile
+                    _ if app.pending_restore.is_some() => {}
+                    KeyCode::Up | KeyCode::Down if app.midi_picker.is_some() => {
+                        if let Some(picker) = &mut app.midi_picker {
+                            picker.move_selection(if key.code == KeyCode::Up { -1 } else { 1 });
+                        }
+                    }
+                    KeyCode::Tab if app.midi_picker.is_some() => {
+                        app.toggle_midi_only();
+                        audio.set_midi_only(app.settings.midi_only);
+                    }
+                    KeyCode::Enter if app.midi_picker.is_some() => {
+                        let port = app
+                            .midi_picker
+                            .take()
+                            .and_then(|picker| picker.choice().cloned());
+                        connect_midi_output(&mut app, &mut audio, &mut midi_out, port);
+                    }
+                    KeyCode::Esc if app.midi_picker.is_some() => app.midi_picker = None,
+                    // Everything else waits until the picker is closed
+                    _ if app.midi_picker.is_some() => {}
+                    KeyCode::Char('K') => {
+                        app.next_sync();
+                        audio.set_sync(app.settings.sync);
+                        if app.settings.sync == sync::SyncMode::Master && app.midi_output.is_none()
+                        {
+                            app.notify(
+                                "Pick a MIDI output with Shift+O to send clock to".to_string(),
+                                false,
+                            );
+                        }
+                    }
+                    KeyCode::Char('O') => match midi_output::ports() {
+                        Ok(ports) => {
+                            app.midi_picker =
+                                Some(ui::MidiPicker::new(ports, app.midi_output.as_deref()));
+                        }
+                        Err(e) => app.notify(format!("MIDI output unavailable: {}", e), false),
+                    },
+                    KeyCode::Char(c @ ('z' | 'y'))
+                        if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                    {
+                        let (done, action) = if c == 'z' {
+                            (app.recorder.undo(), "Undo")
+                        } else {
+                            (app.recorder.redo(), "Redo")
+                        };
+                        if done {
+                            app.notify(
+                                format!("{}: take {}", action, app.recorder.take_label()),
+                                true,
+                            );
+                        }
+                    }
+                    KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        match app.project().save(&app.project_path) {
+                            Ok(()) => {
+                                app.notify(format!("Saved {}", app.project_path.display()), true)
+                            }
+                            Err(e) => app.notify(format!("Save failed: {}", e), false),
+                        }
+                    }
+                    KeyCode::Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        open_project(&mut app, &mut audio);
+                    }
                     KeyCode::Char('q') => break,
+                    KeyCode::Char('r') if app.loop_station => {
+                        if app.looper.is_recording {
+                            app.looper.stop_recording(app.transport.bar_duration());
+                            audio.play_loops(&app.looper, app.transport.bpm);
+                        } else {
+                            app.looper
+                                .start_recording(app.settings.instrument, app.transport.bpm);
+                        }
+                    }
                     KeyCode::Char('r') => {
                         if app.recorder.is_recording {
+                            if app.recorder.is_overdubbing() {
+                                audio.stop_playback();
+                            }
                             app.recorder.stop_recording();
                         } else {
-                            app.recorder.start_recording();
+                            // Capture starts after the count-in, with the
+                            // transport (and metronome) already running. The
+                            // end is worked out once, from the mixer's latest
+                            // position, so clicks and capture agree on the bar
+                            let ticks = audio.playback().transport_ticks();
+                            let (until, count_in) = app.count_in(ticks);
+                            audio.count_in(until);
+                            // Overdubs hear the take from where capture starts
+                            if app.recorder.overdub && app.recorder.current.is_some() {
+                                let take = app.current_take();
+                                let speed = take.tempo_ratio(app.transport.bpm);
+                                audio.play_recording(
+                                    &take.delayed(count_in.mul_f32(speed)),
+                                    speed,
+                                    false,
+                                );
+                            }
+                            app.recorder.start_recording(
+                                app.transport.bpm,
+                                count_in,
+                                app.settings.instrument,
+                            );
                         }
                     }
                     KeyCode::Char('p') => {
-                        if !app.recorder.is_recording && !is_playing {
-                            is_playing = true;
-                            let recording = app.recorder.get_recording();
-                            audio.play_recording(recording);
-                            is_playing = false;
+                        if audio.playback().is_playing() {
+                            audio.stop_playback();
+                        } else if !app.recorder.is_recording {
+                            let take = app.current_take();
+                            let speed = app.playback_speed * take.tempo_ratio(app.transport.bpm);
+                            audio.play_recording(&take, speed, app.loop_playback);
+                        }
+                    }
+                    KeyCode::Char(' ') => {
+                        if app.transport_state == TransportState::Playing {
+                            audio.transport_pause();
+                        } else {
+                            audio.transport_play();
+                        }
+                    }
+                    KeyCode::Esc => audio.transport_stop(),
+                    KeyCode::Char(c @ ('t' | '-' | '=' | '_' | '+')) => {
+                        match c {
+                            't' => app.transport.tap(),
+                            '-' => app.transport.adjust_bpm(-1.0),
+                            '=' => app.transport.adjust_bpm(1.0),
+                            '_' => app.transport.adjust_bpm(-10.0),
+                            _ => app.transport.adjust_bpm(10.0),
+                        }
+                        audio.set_tempo(app.transport.bpm);
+                        // Loops follow tempo changes as they play
+                        if app.loop_station {
+                            audio.play_loops(&app.looper, app.transport.bpm);
+                        }
+                    }
+                    KeyCode::Char('T') => {
+                        app.transport.time_signature = app.transport.time_signature.next();
+                        audio.set_time_signature(app.transport.time_signature);
+                    }
+                    KeyCode::Char('M') => {
+                        app.toggle_metronome();
+                        audio.set_metronome(app.settings.metronome, app.settings.metronome_volume);
+                    }
+                    KeyCode::Char(c @ ('{' | '}')) => {
+                        app.adjust_metronome_volume(if c == '}' { 1 } else { -1 });
+                        audio.set_metronome(app.settings.metronome, app.settings.metronome_volume);
+                    }
+                    KeyCode::Char('C') => app.next_count_in(),
+                    KeyCode::Char('D') if !app.recorder.is_recording => {
+                        app.recorder.overdub = !app.recorder.overdub;
+                    }
+                    KeyCode::Char(c @ ('(' | ')')) => {
+                        app.recorder.select_take(if c == ')' { 1 } else { -1 });
+                    }
+                    KeyCode::Char('Q') => app.quantize.enabled = !app.quantize.enabled,
+                    KeyCode::Char('G') => app.quantize.grid = app.quantize.grid.next(),
+                    KeyCode::Char('S') => app.quantize.next_strength(),
+                    KeyCode::Char('W') => app.quantize.next_swing(),
+                    KeyCode::Char('o') => {
+                        app.loop_playback = !app.loop_playback;
+                    }
+                    KeyCode::Char(c @ ('<' | '>')) => {
+                        app.change_playback_speed(if c == '>' { 1 } else { -1 });
+                    }
+                    KeyCode::Char('w') if !app.recorder.is_recording => {
+                        // The loops and drums go in the render as they'd play
+                        // along with the take
+                        let bpm = app.transport.bpm;
+                        let (drums, drums_looping) =
+                            app.sequencer.arrangement(&app.drum_pad.sounds);
+                        let backing = render::Backing {
+                            bpm,
+                            time_signature: app.transport.time_signature,
+                            loops: app
+                                .looper
+                                .length_at(bpm)
+                                .filter(|_| app.loop_station)
+                                .map(|length| (app.looper.events(bpm), length)),
+                            drums,
+                            drums_looping,
+                        };
+                        let kit = app.drum_pad.kit();
+                        let mut take = app.current_take();
+                        take.kit = kit.map(|kit| kit.name.clone());
+                        let kit = kit::DrumKit::sample_data(kit);
+                        match render::export_take(&take, backing, &app.settings, kit) {
+                            Ok(path) => app.notify(format!("Rendered {}", path.display()), true),
+                            Err(e) => app.notify(format!("Render failed: {}", e), false),
+                        }
+                    }
+                    KeyCode::Char('X') if !app.recorder.is_recording => {
+                        let (drums, _) = app.sequencer.arrangement(&app.drum_pad.sounds);
+                        let kit = app.drum_pad.kit();
+                        match midi_file::export(
+                            &app.current_take(),
+                            &app.looper,
+                            &drums,
+                            kit,
+                            &app.transport,
+                        ) {
+                            Ok(path) => app.notify(format!("Exported {}", path.display()), true),
+                            Err(e) => app.notify(format!("MIDI export failed: {}", e), false),
                         }
                     }
                     KeyCode::Char('m') => {
                         app.ai_mode = !app.ai_mode;
                         if app.ai_mode {
+                            audio.transport_play();
                             app.start_ai_loading("Initializing AI Mode");
                             if let Some(player) = &mut app.gemini_player {
                                 match player.generate_melody(&app.ai_mood).await {
@@ -100,61 +434,155 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         }
                     }
                     KeyCode::Char('1') if !app.ai_mode => {
-                        audio.change_instrument(instrument::InstrumentType::Sine);
-                        app.set_instrument("Sine");
+                        audio.change_instrument(InstrumentType::Sine);
+                        app.set_instrument(InstrumentType::Sine);
                     }
                     KeyCode::Char('2') if !app.ai_mode => {
-                        audio.change_instrument(instrument::InstrumentType::Square);
-                        app.set_instrument("Square");
+                        audio.change_instrument(InstrumentType::Square);
+                        app.set_instrument(InstrumentType::Square);
                     }
                     KeyCode::Char('3') if !app.ai_mode => {
-                        audio.change_instrument(instrument::InstrumentType::Triangle);
-                        app.set_instrument("Triangle");
+                        audio.change_instrument(InstrumentType::Triangle);
+                        app.set_instrument(InstrumentType::Triangle);
                     }
                     KeyCode::Char('4') if !app.ai_mode => {
-                        audio.change_instrument(instrument::InstrumentType::Saw);
-                        app.set_instrument("Saw");
+                        audio.change_instrument(InstrumentType::Saw);
+                        app.set_instrument(InstrumentType::Saw);
+                    }
+                    KeyCode::Char('5') if !app.ai_mode => {
+                        app.toggle_lofi();
+                        audio.set_lofi(app.settings.lofi);
                     }
+                    KeyCode::Char('e') if !app.drum_pad.is_drum_mode => {
+                        app.next_envelope_param();
+                    }
+                    KeyCode::Char(c @ ('[' | ']')) if !app.drum_pad.is_drum_mode => {
+                        let envelope = app.adjust_envelope(if c == ']' { 1 } else { -1 });
+                        audio.set_envelope(app.settings.instrument, envelope);
+                    }
+                    KeyCode::Char('n') if app.drum_pad.is_drum_mode => {
+                        let kit = app.drum_pad.next_kit();
+                        audio.set_kit(kit);
+                        audio.set_drums(&app.sequencer, &app.drum_pad.sounds, false);
+                    }
+                    KeyCode::Char('e') if app.drum_pad.is_drum_mode => {
+                        app.sequencer.visible = !app.sequencer.visible;
+                    }
+                    KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+                        if app.drum_pad.is_drum_mode && app.sequencer.visible =>
+                    {
+                        let (rows, steps) = match key.code {
+                            KeyCode::Up => (-1, 0),
+                            KeyCode::Down => (1, 0),
+                            KeyCode::Left => (0, -1),
+                            _ => (0, 1),
+                        };
+                        let row_count = app.drum_pad.pads().len();
+                        app.sequencer.move_cursor(rows, steps, row_count);
+                    }
+                    KeyCode::Enter | KeyCode::Char('A' | ',' | '.' | 'u' | 'U' | 'H' | 'F')
+                        if app.drum_pad.is_drum_mode && app.sequencer.visible =>
+                    {
+                        let snare = DrumPad::key_for(DrumSound::Snare).unwrap_or('x');
+                        let hihat = DrumPad::key_for(DrumSound::HiHat).unwrap_or('c');
+                        match (key.code, app.grid_key()) {
+                            (KeyCode::Enter, Some(pad)) => app.sequencer.toggle(pad),
+                            (KeyCode::Char('A'), Some(pad)) => app.sequencer.cycle_velocity(pad),
+                            (KeyCode::Char('u'), Some(pad)) => app.sequencer.euclid_row(pad),
+                            (KeyCode::Char('U'), Some(pad)) => app.sequencer.rotate_row(pad),
+                            (KeyCode::Char('H'), _) => app.sequencer.humanize(&[hihat, snare]),
+                            (KeyCode::Char('F'), _) => app.sequencer.random_fill(snare, hihat),
+                            (KeyCode::Char(','), _) => app.sequencer.resize(-4),
+                            (KeyCode::Char('.'), _) => app.sequencer.resize(4),
+                            _ => {}
+                        }
+                        audio.set_drums(&app.sequencer, &app.drum_pad.sounds, false);
+                    }
+                    // Pattern and song changes wait for the next bar line
+                    KeyCode::F(n @ 1..=8) if app.drum_pad.is_drum_mode => {
+                        app.sequencer.select(n as usize - 1);
+                        audio.set_drums(&app.sequencer, &app.drum_pad.sounds, true);
+                    }
+                    KeyCode::F(9) if app.drum_pad.is_drum_mode => {
+                        app.sequencer.song_mode = !app.sequencer.song_mode;
+                        audio.set_drums(&app.sequencer, &app.drum_pad.sounds, true);
+                    }
+                    KeyCode::Char('i') | KeyCode::Backspace if app.drum_pad.is_drum_mode => {
+                        if key.code == KeyCode::Backspace {
+                            app.sequencer.remove_from_song();
+                        } else {
+                            app.sequencer.append_to_song();
+                        }
+                        if app.sequencer.song_mode {
+                            audio.set_drums(&app.sequencer, &app.drum_pad.sounds, false);
+                        }
+                    }
+                    KeyCode::F(n @ 1..=4) if app.loop_station => {
+                        app.looper.toggle_loop(n as usize - 1);
+                        audio.play_loops(&app.looper, app.transport.bpm);
+                    }
+                    KeyCode::F(n @ 5..=8) if app.loop_station && !app.looper.is_recording => {
+                        app.looper.clear_loop(n as usize - 5);
+                        audio.play_loops(&app.looper, app.transport.bpm);
+                    }
+                    // Keyboard -> Drums -> Loop station -> Keyboard
                     KeyCode::Tab => {
-                        app.drum_pad.toggle_mode();
+                        if app.drum_pad.is_drum_mode {
+                            app.drum_pad.toggle_mode();
+                            app.loop_station = true;
+                            audio.play_loops(&app.looper, app.transport.bpm);
+                        } else if app.loop_station {
+                            app.looper.stop_recording(app.transport.bar_duration());
+                            app.loop_station = false;
+                            audio.stop_loops();
+                        } else {
+                            app.drum_pad.toggle_mode();
+                        }
+                    }
+                    KeyCode::Char(c @ ('z' | 'x' | 'c' | 'v')) if !app.drum_pad.is_drum_mode => {
+                        match c {
+                            'z' => app.shift_keyboard(-1, 0),
+                            'x' => app.shift_keyboard(1, 0),
+                            'c' => app.shift_keyboard(0, -1),
+                            _ => app.shift_keyboard(0, 1),
+                        }
                     }
                     KeyCode::Char(c) => {
                         if app.drum_pad.is_drum_mode {
                             if let Some(drum) = app.drum_pad.hit_drum(c) {
-                                audio.play_drum(drum);
-                                app.drum_pad.active_beats.push(c);
+                                play_drum(&mut app, &mut audio, c, drum, 1.0);
                             }
-                        } else {
-                            app.keyboard.press_key(c);
-                            audio.play_note(c);
-                            app.log_keystroke();
-
-                            if app.recorder.is_recording {
-                                app.recorder.record_note(c);
-                            }
-
-                            if app.keyboard.active_keys.len() > 1 {
-                                audio.play_chord(&app.keyboard.active_keys);
+                        } else if let Some(note) = app.keyboard.note(c) {
+                            // A key pressed again before it was let go, perhaps
+                            // after an octave change, ends its last note first
+                            if let Some(old) = app.keyboard.release_key(c) {
+                                release_note(&mut app, &mut audio, old);
                             }
+                            let held = app.key_releases;
+                            play_key(&mut app, &mut audio, c, note, 1.0, held);
                         }
                     }
                     _ => {}
-                }
+                },
+                _ => {}
             }
         }
 
-        // Handle AI-generated notes
-        if app.ai_mode {
+        // Handle AI-generated notes, which play along with the transport
+        if app.ai_mode && app.transport_state == TransportState::Playing {
             if let Some(player) = &mut app.gemini_player {
-                if let Some((note, duration)) = player.get_next_note() {
-                    // Update keyboard state to show the pressed key
-                    app.keyboard.press_key(note);
-
-                    // Play the note
-                    audio.play_note(note);
+                if let Some((key, _duration)) = player.get_next_note(app.transport.bpm) {
+                    if let Some(note) = app.keyboard.note(key) {
+                        if let Some(old) = app.keyboard.release_key(key) {
+                            release_note(&mut app, &mut audio, old);
+                        }
+                        // Update keyboard state to show the pressed key
+                        app.keyboard.press_key(key, note);
 
-                    // Wait for the duration
-                    std::thread::sleep(duration);
+                        // Play the note; get_next_note holds the next one
+                        // back until this one's duration has passed
+                        audio.note_on(note, 1.0);
+                    }
 
                     // Optional: Update keystroke count for AI-generated notes too
                     app.log_keystroke();
@@ -163,6 +591,163 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if app.pending_restore.is_none() {
+        if let Err(e) = project::autosave(&app.project()) {
+            warn!("Autosave failed: {}", e);
+        }
+    }
     app.cleanup()?;
     Ok(())
 }
+
+/// Keys that act again while held down.
+fn repeats(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::Char('-' | '=' | '_' | '+')
+            | KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Left
+            | KeyCode::Right
+    )
+}
+
+/// Loads the project at the app's project path and brings the audio engine
+/// in line with it.
+fn open_project(app: &mut ui::App, audio: &mut audio::AudioEngine) {
+    match project::Project::load(&app.project_path) {
+        Ok(project) => {
+            apply_project(app, audio, project);
+            app.notify(format!("Opened {}", app.project_path.display()), true);
+        }
+        Err(e) => app.notify(format!("Open failed: {}", e), false),
+    }
+}
+
+fn apply_project(app: &mut ui::App, audio: &mut audio::AudioEngine, project: project::Project) {
+    app.open_project(project);
+    audio.stop_playback();
+    audio.apply_settings(&app.settings);
+    audio.set_tempo(app.transport.bpm);
+    audio.set_time_signature(app.transport.time_signature);
+    audio.set_drums(&app.sequencer, &app.drum_pad.sounds, false);
+    if app.loop_station {
+        audio.play_loops(&app.looper, app.transport.bpm);
+    }
+}
+
+/// Sounds a drum and records the hit. `pad` is the pad to light up.
+fn play_drum(
+    app: &mut ui::App,
+    audio: &mut audio::AudioEngine,
+    pad: char,
+    drum: DrumSound,
+    velocity: f32,
+) {
+    audio.play_drum(drum, velocity);
+    app.drum_pad.active_beats.push(pad);
+    if app.recorder.is_recording {
+        app.recorder.record_drum(drum);
+    }
+}
+
+/// Starts `note` on a keyboard key and records it. Held notes sound until
+/// `release_note`; the others are released after a short tap.
+fn play_key(
+    app: &mut ui::App,
+    audio: &mut audio::AudioEngine,
+    key: char,
+    note: u8,
+    velocity: f32,
+    held: bool,
+) {
+    if held {
+        app.keyboard.hold_key(key, note);
+    } else {
+        app.keyboard.press_key(key, note);
+    }
+    audio.note_on(note, velocity);
+    app.log_keystroke();
+
+    // Recordings keep the key along with how far off its own note it was
+    let shift = keyboard::key_note(key, 0).map_or(0, |base| note as i32 - base as i32) as i8;
+    if app.recorder.is_recording {
+        app.recorder.record_note(key, shift);
+    }
+    if app.looper.is_recording {
+        let position = app.loop_playhead.map(|(position, _)| position);
+        app.looper
+            .record_note(key, shift, app.settings.instrument, position);
+    }
+}
+
+fn release_note(app: &mut ui::App, audio: &mut audio::AudioEngine, note: u8) {
+    audio.note_off(note);
+    app.recorder.record_release(note);
+    app.looper.record_release(note);
+}
+
+/// Sends notes to the named MIDI port, or back to the internal synth only
+/// with None. The old port is let go of first.
+fn connect_midi_output(
+    app: &mut ui::App,
+    audio: &mut audio::AudioEngine,
+    midi_out: &mut Option<midi_output::MidiOut>,
+    port: Option<String>,
+) {
+    if midi_out.is_some() {
+        audio.set_midi_output(None);
+        *midi_out = None;
+    }
+    let Some(port) = port else {
+        app.set_midi_output(None);
+        return;
+    };
+    match midi_output::MidiOut::connect(&port) {
+        Ok((out, queue)) => {
+            audio.set_midi_output(Some(queue));
+            *midi_out = Some(out);
+            app.notify(format!("Sending MIDI to {}", port), true);
+            app.set_midi_output(Some(port));
+        }
+        Err(e) => {
+            // Keep the preference so the port is tried again next launch
+            app.midi_output = None;
+            app.notify(format!("MIDI output failed: {}", e), false);
+        }
+    }
+}
+
+/// Plays a note from a MIDI controller: drums for channel 10, or any
+/// channel in drum mode, and otherwise keyboard notes at the pitch the
+/// controller sent.
+fn handle_midi(app: &mut ui::App, audio: &mut audio::AudioEngine, event: MidiEvent) {
+    match event {
+        MidiEvent::NoteOn {
+            channel,
+            note,
+            velocity,
+        } if channel == midi_file::DRUM_CHANNEL || app.drum_pad.is_drum_mode => {
+            let drum = midi_file::drum_sound(note);
+            if let Some(pad) = DrumPad::key_for(drum) {
+                // Kit samples standing in for the drum play in its place
+                let sound = app.drum_pad.sounds.get(&pad).copied().unwrap_or(drum);
+                play_drum(app, audio, pad, sound, velocity);
+            }
+        }
+        MidiEvent::NoteOn { note, velocity, .. } => {
+            let (key, _) = midi_file::keyboard_key(note);
+            play_key(app, audio, key, note, velocity, true);
+        }
+        MidiEvent::NoteOff { channel, note } if channel != midi_file::DRUM_CHANNEL => {
+            // Notes an octave apart share a key, which shows whichever
+            // started last
+            let (key, _) = midi_file::keyboard_key(note);
+            if app.keyboard.sounding(key) == Some(note) {
+                app.keyboard.release_key(key);
+            }
+            release_note(app, audio, note);
+        }
+        MidiEvent::NoteOff { .. } => {}
+    }
+}
@@ -0,0 +1,697 @@
+use crate::drums::DrumSound;
+use crate::instrument::InstrumentType;
+use crate::keyboard;
+use crate::kit::DrumKit;
+use crate::looper::Looper;
+use crate::recorder::{Note, Take, TAP_LENGTH};
+use crate::sequencer::{PatternHits, STEP_TICKS};
+use crate::transport::{self, Transport, DEFAULT_BPM, MAX_BPM, MIN_BPM, PPQN};
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Channel 10 counting from one, where General MIDI expects percussion
+pub const DRUM_CHANNEL: u8 = 9;
+const NOTE_VELOCITY: u8 = 100;
+// Drum hits have no length of their own; a 32nd note is enough to sound
+const DRUM_TICKS: u64 = PPQN / 8;
+const LOOP_NAMES: [&str; 4] = ["Loop 1", "Loop 2", "Loop 3", "Loop 4"];
+// General MIDI percussion for kit samples that don't stand in for a
+// built-in sound: toms, cowbell, tambourine, ride, open hat, high agogo
+const SAMPLE_NOTES: [u8; 9] = [45, 47, 48, 50, 56, 54, 51, 46, 67];
+
+// Events at absolute ticks, before they are turned into deltas
+type Events<'a> = Vec<(u64, TrackEventKind<'a>)>;
+
+/// General MIDI percussion note for a drum. Kit samples named after a
+/// built-in sound take its note.
+pub fn drum_note(drum: DrumSound, kit: Option<&DrumKit>) -> u8 {
+    match drum {
+        DrumSound::Kick => 36,
+        DrumSound::Snare => 38,
+        DrumSound::HiHat => 42,
+        DrumSound::Clap => 39,
+        DrumSound::Cymbal => 49,
+        DrumSound::Sample(index) => kit
+            .and_then(|kit| kit.samples.get(index))
+            .and_then(|sample| {
+                DrumSound::BUILT_IN
+                    .into_iter()
+                    .find(|sound| sound.name().eq_ignore_ascii_case(&sample.name))
+            })
+            .map_or(SAMPLE_NOTES[index % SAMPLE_NOTES.len()], |sound| {
+                drum_note(sound, None)
+            }),
+    }
+}
+
+/// The closest drum for a General MIDI percussion note.
+pub fn drum_sound(note: u8) -> DrumSound {
+    match note {
+        35 | 36 | 41 | 43 => DrumSound::Kick,
+        37 | 38 | 40 | 45 | 47 | 48 | 50 => DrumSound::Snare,
+        39 | 54 | 56 | 58 => DrumSound::Clap,
+        49 | 51..=53 | 55 | 57 | 59 => DrumSound::Cymbal,
+        _ => DrumSound::HiHat,
+    }
+}
+
+/// Keyboard key for a MIDI note, and the shift in semitones that makes
+/// the key play it. Notes outside the keyboard are folded into it by
+/// octaves to find the key, and ones between keys go to the closest.
+pub fn keyboard_key(note: u8) -> (char, i8) {
+    let lowest = keyboard::LAYOUT[0].1;
+    let highest = keyboard::LAYOUT[keyboard::LAYOUT.len() - 1].1;
+
+    let mut folded = note;
+    while folded < lowest {
+        folded += 12;
+    }
+    while folded > highest && folded >= lowest + 12 {
+        folded -= 12;
+    }
+    let (key, key_note) = keyboard::LAYOUT
+        .into_iter()
+        .min_by_key(|&(key, key_note)| (key_note.abs_diff(folded), key_note, key))
+        .unwrap_or(keyboard::LAYOUT[0]);
+    (key, (note as i32 - key_note as i32) as i8)
+}
+
+/// General MIDI program closest to each waveform.
+pub fn program(instrument: InstrumentType) -> u8 {
+    match instrument {
+        InstrumentType::Sine => 79,     // Ocarina
+        InstrumentType::Square => 80,   // Lead 1 (square)
+        InstrumentType::Triangle => 73, // Flute
+        InstrumentType::Saw => 81,      // Lead 2 (sawtooth)
+    }
+}
+
+fn instrument(program: u8) -> Option<InstrumentType> {
+    [
+        InstrumentType::Sine,
+        InstrumentType::Square,
+        InstrumentType::Triangle,
+        InstrumentType::Saw,
+    ]
+    .into_iter()
+    .find(|&instrument| self::program(instrument) == program)
+}
+
+fn note<'a>(events: &mut Events<'a>, channel: u8, key: u8, velocity: u8, at: u64, length: u64) {
+    let channel = u4::new(channel);
+    let key = u7::new(key);
+    events.push((
+        at,
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn {
+                key,
+                vel: u7::new(velocity.max(1)),
+            },
+        },
+    ));
+    events.push((
+        at + length.max(1),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOff {
+                key,
+                vel: u7::new(0),
+            },
+        },
+    ));
+}
+
+fn program_change<'a>(events: &mut Events<'a>, channel: u8, instrument: InstrumentType, at: u64) {
+    events.push((
+        at,
+        TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::ProgramChange {
+                program: u7::new(program(instrument)),
+            },
+        },
+    ));
+}
+
+/// Orders events in time and turns them into a track. Note offs go before
+/// note ons on the same tick so repeated notes aren't cut short.
+fn track<'a>(name: &'a str, mut events: Events<'a>) -> Vec<TrackEvent<'a>> {
+    events.sort_by_key(|(at, kind)| {
+        let starts = matches!(
+            kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            }
+        );
+        (*at, starts)
+    });
+
+    let mut track = vec![TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+    }];
+    let mut last = 0;
+    for (at, kind) in events
+        .into_iter()
+        .chain([(0, TrackEventKind::Meta(MetaMessage::EndOfTrack))])
+    {
+        let at = at.max(last);
+        track.push(TrackEvent {
+            delta: u28::new((at - last) as u32),
+            kind,
+        });
+        last = at;
+    }
+    track
+}
+
+/// Keyboard notes on channel 1 and drum hits on channel 10, with a program
+/// change wherever the instrument does.
+fn take_events<'a>(take: &Take, bpm: f32, kit: Option<&DrumKit>) -> Events<'a> {
+    let ticks = |time: Duration| transport::duration_to_ticks(time, bpm);
+    let mut events = Vec::new();
+    let mut current = None;
+    let mut sent = None;
+    for event in &take.notes {
+        match *event {
+            Note::Key {
+                timestamp,
+                duration,
+                instrument,
+                ..
+            } => {
+                let Some(key) = event.pitch() else {
+                    continue;
+                };
+                if let Some(instrument) = instrument.or(current).filter(|&i| Some(i) != sent) {
+                    program_change(&mut events, 0, instrument, ticks(timestamp));
+                    sent = Some(instrument);
+                }
+                note(
+                    &mut events,
+                    0,
+                    key,
+                    NOTE_VELOCITY,
+                    ticks(timestamp),
+                    ticks(duration),
+                );
+            }
+            Note::Drum { drum, timestamp } => {
+                let key = drum_note(drum, kit);
+                note(
+                    &mut events,
+                    DRUM_CHANNEL,
+                    key,
+                    NOTE_VELOCITY,
+                    ticks(timestamp),
+                    DRUM_TICKS,
+                );
+            }
+            Note::Instrument { instrument, .. } => current = Some(instrument),
+        }
+    }
+    events
+}
+
+/// One pass through the drum arrangement on channel 10.
+fn drum_events<'a>(patterns: &[PatternHits], kit: Option<&DrumKit>) -> Events<'a> {
+    let mut events = Vec::new();
+    let steps = patterns.iter().flat_map(|pattern| &pattern.steps);
+    for (step, hits) in steps.enumerate() {
+        for &(drum, gain) in hits {
+            let velocity = (gain * 127.0).round() as u8;
+            let at = step as u64 * STEP_TICKS;
+            note(
+                &mut events,
+                DRUM_CHANNEL,
+                drum_note(drum, kit),
+                velocity,
+                at,
+                DRUM_TICKS,
+            );
+        }
+    }
+    events
+}
+
+/// Builds a format 1 Standard MIDI File: a tempo track, then the take,
+/// one pass of each loop and one pass of the drum arrangement, leaving out
+/// whichever are empty. Each loop gets its own channel from 2 up.
+pub fn build<'a>(
+    take: &Take,
+    looper: &Looper,
+    drums: &[PatternHits],
+    kit: Option<&DrumKit>,
+    transport: &Transport,
+) -> Smf<'a> {
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(PPQN as u16)),
+    ));
+
+    let time_signature = transport.time_signature;
+    let tempo = (60_000_000.0 / transport.bpm).round() as u32;
+    smf.tracks.push(track(
+        "devtunes",
+        vec![
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo)))),
+            (
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(
+                    time_signature.beats as u8,
+                    time_signature.unit.trailing_zeros() as u8,
+                    24,
+                    8,
+                )),
+            ),
+        ],
+    ));
+
+    // Takes from before tempo was saved play at whatever the tempo is
+    let take_bpm = take.bpm.unwrap_or(transport.bpm);
+    let events = take_events(take, take_bpm, kit);
+    if !events.is_empty() {
+        smf.tracks.push(track("Take", events));
+    }
+
+    for (index, (loop_track, name)) in looper.loops.iter().zip(LOOP_NAMES).enumerate() {
+        // Cleared slots keep their place, and their channel, but have no track
+        if loop_track.notes.is_empty() {
+            continue;
+        }
+        let channel = index as u8 + 1;
+        let ticks = |time: Duration| transport::duration_to_ticks(time, looper.bpm);
+        let mut events = Vec::new();
+        program_change(&mut events, channel, loop_track.instrument, 0);
+        for event in &loop_track.notes {
+            if let Note::Key {
+                timestamp,
+                duration,
+                ..
+            } = *event
+            {
+                if let Some(key) = event.pitch() {
+                    note(
+                        &mut events,
+                        channel,
+                        key,
+                        NOTE_VELOCITY,
+                        ticks(timestamp),
+                        ticks(duration),
+                    );
+                }
+            }
+        }
+        if events.len() > 1 {
+            smf.tracks.push(track(name, events));
+        }
+    }
+
+    let events = drum_events(drums, kit);
+    if !events.is_empty() {
+        smf.tracks.push(track("Drums", events));
+    }
+    smf
+}
+
+/// Writes the session to `devtunes-<timestamp>.mid` in the working
+/// directory.
+pub fn export(
+    take: &Take,
+    looper: &Looper,
+    drums: &[PatternHits],
+    kit: Option<&DrumKit>,
+    transport: &Transport,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = PathBuf::from(format!("devtunes-{}.mid", timestamp));
+    build(take, looper, drums, kit, transport).save(&path)?;
+    Ok(path)
+}
+
+/// A track of a MIDI file, as offered for import.
+pub struct TrackInfo {
+    pub name: String,
+    pub notes: usize,
+    pub drums: usize,
+}
+
+/// Name and note counts of every track, in file order.
+pub fn track_list(path: &Path) -> Result<Vec<TrackInfo>, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let smf = Smf::parse(&data)?;
+    Ok(smf
+        .tracks
+        .iter()
+        .map(|track| {
+            let mut info = TrackInfo {
+                name: String::new(),
+                notes: 0,
+                drums: 0,
+            };
+            for event in track {
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                        info.name = String::from_utf8_lossy(name).into_owned();
+                    }
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOn { vel, .. },
+                    } if vel > 0 => {
+                        if channel == DRUM_CHANNEL {
+                            info.drums += 1;
+                        } else {
+                            info.notes += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            info
+        })
+        .collect())
+}
+
+// Converts ticks to time through the file's tempo changes
+struct TempoMap {
+    // Tick each tempo starts at, with its microseconds per quarter note
+    changes: Vec<(u64, u32)>,
+    timing: Timing,
+}
+
+impl TempoMap {
+    fn new(smf: &Smf) -> Self {
+        let mut changes = Vec::new();
+        for track in &smf.tracks {
+            let mut at = 0;
+            for event in track {
+                at += event.delta.as_int() as u64;
+                // A zero tempo can't be played; the one before it stands
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    if tempo.as_int() > 0 {
+                        changes.push((at, tempo.as_int()));
+                    }
+                }
+            }
+        }
+        changes.sort_by_key(|&(at, _)| at);
+        if changes.first().is_none_or(|&(at, _)| at > 0) {
+            changes.insert(0, (0, (60_000_000.0 / DEFAULT_BPM) as u32));
+        }
+        Self {
+            changes,
+            timing: smf.header.timing,
+        }
+    }
+
+    /// Starting tempo, within what the transport can run at.
+    fn bpm(&self) -> f32 {
+        (60_000_000.0 / self.changes[0].1 as f32).clamp(MIN_BPM, MAX_BPM)
+    }
+
+    fn time(&self, tick: u64) -> Duration {
+        let ppqn = match self.timing {
+            Timing::Metrical(ppqn) => ppqn.as_int().max(1) as f64,
+            Timing::Timecode(fps, subframes) => {
+                let per_second = fps.as_f32() as f64 * subframes as f64;
+                return Duration::from_secs_f64(tick as f64 / per_second.max(1.0));
+            }
+        };
+        let mut seconds = 0.0;
+        for (i, &(start, tempo)) in self.changes.iter().enumerate() {
+            if start >= tick {
+                break;
+            }
+            let end = self
+                .changes
+                .get(i + 1)
+                .map_or(tick, |&(next, _)| next.min(tick));
+            seconds += (end - start) as f64 / ppqn * tempo as f64 / 1_000_000.0;
+        }
+        Duration::from_secs_f64(seconds)
+    }
+}
+
+/// Turns track `index` of a MIDI file into a take for devtunes' own
+/// instruments. Drum channel notes become drum hits, program changes
+/// that match a waveform become instrument changes.
+pub fn import(path: &Path, index: usize) -> Result<Take, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let smf = Smf::parse(&data)?;
+    let track = smf
+        .tracks
+        .get(index)
+        .ok_or_else(|| format!("No track {} (the file has {})", index + 1, smf.tracks.len()))?;
+    let tempo = TempoMap::new(&smf);
+
+    let mut notes = Vec::new();
+    // Note index and start of each sounding (channel, note)
+    let mut held = HashMap::new();
+    let mut at = 0;
+    for event in track {
+        at += event.delta.as_int() as u64;
+        let TrackEventKind::Midi { channel, message } = event.kind else {
+            continue;
+        };
+        let timestamp = tempo.time(at);
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                if channel == DRUM_CHANNEL {
+                    let drum = drum_sound(key.as_int());
+                    notes.push(Note::Drum { drum, timestamp });
+                } else {
+                    let (key_char, shift) = keyboard_key(key.as_int());
+                    held.insert((channel, key), notes.len());
+                    notes.push(Note::Key {
+                        key: key_char,
+                        timestamp,
+                        duration: TAP_LENGTH,
+                        instrument: None,
+                        shift,
+                    });
+                }
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                if let Some(index) = held.remove(&(channel, key)) {
+                    let note: &mut Note = &mut notes[index];
+                    note.set_duration(timestamp.saturating_sub(note.timestamp()));
+                }
+            }
+            MidiMessage::ProgramChange { program } if channel != DRUM_CHANNEL => {
+                if let Some(instrument) = instrument(program.as_int()) {
+                    notes.push(Note::Instrument {
+                        instrument,
+                        timestamp,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let take = Take {
+        notes,
+        length: Duration::ZERO,
+        bpm: Some(tempo.bpm()),
+        kit: None,
+    };
+    Ok(Take {
+        length: take.length(),
+        ..take
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::looper::Loop;
+    use crate::transport::TimeSignature;
+
+    // Start tick, channel, note, velocity and length in ticks
+    type Played = (u64, u8, u8, u8, u64);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn key(key: char, at: u64, length: u64, shift: i8) -> Note {
+        Note::Key {
+            key,
+            timestamp: ms(at),
+            duration: ms(length),
+            instrument: None,
+            shift,
+        }
+    }
+
+    /// Notes and program changes of a parsed track, with note ons paired
+    /// up with their offs.
+    fn played(track: &[TrackEvent]) -> (Vec<Played>, Vec<(u64, u8, u8)>) {
+        let mut notes: Vec<Played> = Vec::new();
+        let mut programs = Vec::new();
+        let mut at = 0;
+        for event in track {
+            at += event.delta.as_int() as u64;
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            let channel = channel.as_int();
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    notes.push((at, channel, key.as_int(), vel.as_int(), 0));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    let note = notes
+                        .iter_mut()
+                        .find(|n| n.1 == channel && n.2 == key.as_int() && n.4 == 0)
+                        .expect("note off without a note on");
+                    note.4 = at - note.0;
+                }
+                MidiMessage::ProgramChange { program } => {
+                    programs.push((at, channel, program.as_int()));
+                }
+                _ => {}
+            }
+        }
+        (notes, programs)
+    }
+
+    fn track_name<'a>(track: &[TrackEvent<'a>]) -> &'a [u8] {
+        match track[0].kind {
+            TrackEventKind::Meta(MetaMessage::TrackName(name)) => name,
+            _ => panic!("track doesn't start with its name"),
+        }
+    }
+
+    #[test]
+    fn export_round_trips() {
+        // At 120 BPM a beat is 500 ms, or PPQN ticks
+        let take = Take {
+            notes: vec![
+                Note::Instrument {
+                    instrument: InstrumentType::Square,
+                    timestamp: ms(0),
+                },
+                key('a', 0, 250, 0),
+                Note::Drum {
+                    drum: DrumSound::Snare,
+                    timestamp: ms(500),
+                },
+                Note::Instrument {
+                    instrument: InstrumentType::Saw,
+                    timestamp: ms(600),
+                },
+                key('d', 750, 500, 12),
+            ],
+            length: ms(1500),
+            bpm: Some(120.0),
+            kit: None,
+        };
+        let mut looper = Looper::new();
+        looper.bpm = 120.0;
+        looper.loops.push(Loop {
+            notes: vec![key('g', 250, 250, -1)],
+            instrument: InstrumentType::Triangle,
+            is_active: true,
+        });
+        let mut steps = vec![Vec::new(); 16];
+        steps[0].push((DrumSound::Kick, 1.0));
+        steps[2].push((DrumSound::HiHat, 0.5));
+        let drums = [PatternHits { slot: 0, steps }];
+        let mut transport = Transport::new();
+        transport.bpm = 120.0;
+        transport.time_signature = TimeSignature { beats: 3, unit: 4 };
+
+        let mut data = Vec::new();
+        build(&take, &looper, &drums, None, &transport)
+            .write_std(&mut data)
+            .unwrap();
+        let smf = Smf::parse(&data).unwrap();
+
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.header.timing, Timing::Metrical(u15::new(PPQN as u16)));
+        let names = smf.tracks.iter().map(|t| track_name(t)).collect::<Vec<_>>();
+        assert_eq!(names, [&b"devtunes"[..], b"Take", b"Loop 1", b"Drums"]);
+
+        let metas = smf.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => Some((0, tempo.as_int())),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(beats, unit, ..)) => {
+                    Some((beats as u32, unit as u32))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(metas, [(0, 500_000), (3, 2)]);
+
+        let beat = PPQN;
+        let (notes, programs) = played(&smf.tracks[1]);
+        assert_eq!(
+            notes,
+            [
+                (0, 0, 69, NOTE_VELOCITY, beat / 2),
+                (beat, DRUM_CHANNEL, 38, NOTE_VELOCITY, DRUM_TICKS),
+                (beat * 3 / 2, 0, 84, NOTE_VELOCITY, beat),
+            ]
+        );
+        assert_eq!(programs, [(0, 0, 80), (beat * 3 / 2, 0, 81)]);
+
+        let (notes, programs) = played(&smf.tracks[2]);
+        assert_eq!(notes, [(beat / 2, 1, 75, NOTE_VELOCITY, beat / 2)]);
+        assert_eq!(programs, [(0, 1, 73)]);
+
+        // General MIDI kick and closed hat, on channel 10
+        let (notes, _) = played(&smf.tracks[3]);
+        assert_eq!(
+            notes,
+            [
+                (0, DRUM_CHANNEL, 36, 127, DRUM_TICKS),
+                (2 * STEP_TICKS, DRUM_CHANNEL, 42, 64, DRUM_TICKS),
+            ]
+        );
+    }
+
+    fn tempo_map(tempos: &[u32]) -> TempoMap {
+        let mut track = tempos
+            .iter()
+            .map(|&tempo| TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo))),
+            })
+            .collect::<Vec<_>>();
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        TempoMap::new(&Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(96))),
+            tracks: vec![track],
+        })
+    }
+
+    #[test]
+    fn zero_tempo_is_ignored() {
+        let tempo = tempo_map(&[0]);
+        assert_eq!(tempo.bpm(), DEFAULT_BPM);
+        let beat = 60.0 / DEFAULT_BPM as f64;
+        assert!((tempo.time(96).as_secs_f64() - beat).abs() < 1e-6);
+
+        let tempo = tempo_map(&[1_000_000, 0]);
+        assert_eq!(tempo.bpm(), 60.0);
+        assert!((tempo.time(96).as_secs_f64() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tempo_is_clamped_to_transport_range() {
+        assert_eq!(tempo_map(&[1]).bpm(), MAX_BPM);
+        assert_eq!(tempo_map(&[0xff_ffff]).bpm(), MIN_BPM);
+    }
+}
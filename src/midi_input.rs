@@ -0,0 +1,178 @@
+use crate::sync::{self, SyncSender, TempoMeter};
+use log::warn;
+use midir::{MidiInput, MidiInputConnection};
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+const CLIENT_NAME: &str = "devtunes";
+/// Port other programs can connect to, e.g. with `aconnect`
+pub const VIRTUAL_PORT: &str = "devtunes in";
+
+#[derive(Clone, Copy, Debug)]
+pub enum MidiEvent {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        /// From 0 to 1
+        velocity: f32,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+}
+
+impl MidiEvent {
+    /// Decodes note messages; anything else is ignored.
+    pub fn parse(message: &[u8]) -> Option<MidiEvent> {
+        let (&status, data) = message.split_first()?;
+        let channel = status & 0x0f;
+        match (status & 0xf0, data) {
+            (0x90, &[note, velocity, ..]) if velocity > 0 => Some(MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity: velocity as f32 / 127.0,
+            }),
+            // A note on with no velocity is a note off
+            (0x90, &[note, 0, ..]) | (0x80, &[note, ..]) => {
+                Some(MidiEvent::NoteOff { channel, note })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Listens to every MIDI input port there is when devtunes starts, plus a
+/// virtual port that controllers and other programs can be connected to.
+/// Clock messages go to `sync`, notes to `poll`.
+pub struct MidiInputs {
+    events: Receiver<MidiEvent>,
+    // Input stops when these are dropped
+    _connections: Vec<MidiInputConnection<()>>,
+    /// Names of the ports being listened to
+    pub ports: Vec<String>,
+}
+
+impl MidiInputs {
+    pub fn open(sync: SyncSender) -> Result<Self, Box<dyn Error>> {
+        let (sender, events) = mpsc::channel();
+        let mut connections = Vec::new();
+        let mut ports = Vec::new();
+
+        let input = MidiInput::new(CLIENT_NAME)?;
+        for port in input.ports() {
+            let name = input.port_name(&port)?;
+            // Don't listen to ourselves
+            if name.contains(CLIENT_NAME) {
+                continue;
+            }
+            // Each connection takes a client of its own
+            let client = MidiInput::new(CLIENT_NAME)?;
+            match client.connect(
+                &port,
+                VIRTUAL_PORT,
+                forward(sender.clone(), sync.clone()),
+                (),
+            ) {
+                Ok(connection) => {
+                    connections.push(connection);
+                    ports.push(name);
+                }
+                Err(e) => warn!("Failed to open MIDI input {}: {}", name, e),
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            use midir::os::unix::VirtualInput;
+            let client = MidiInput::new(CLIENT_NAME)?;
+            match client.create_virtual(VIRTUAL_PORT, forward(sender, sync), ()) {
+                Ok(connection) => {
+                    connections.push(connection);
+                    ports.push(VIRTUAL_PORT.to_string());
+                }
+                Err(e) => warn!("Failed to create the virtual MIDI port: {}", e),
+            }
+        }
+
+        Ok(Self {
+            events,
+            _connections: connections,
+            ports,
+        })
+    }
+
+    /// Events that have arrived since the last poll.
+    pub fn poll(&self) -> impl Iterator<Item = MidiEvent> + '_ {
+        self.events.try_iter()
+    }
+}
+
+// Runs on midir's thread, handing notes over to the UI loop and clock to
+// the mixer
+fn forward(
+    sender: Sender<MidiEvent>,
+    sync: SyncSender,
+) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    let mut meter = TempoMeter::new();
+    move |_, message, _| {
+        if let Some(message) = sync::parse(message, &mut meter) {
+            if let Ok(mut sync) = sync.lock() {
+                let _ = sync.push(message);
+            }
+        } else if let Some(event) = MidiEvent::parse(message) {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_on_carries_channel_and_velocity() {
+        let event = MidiEvent::parse(&[0x93, 60, 127]);
+        assert!(matches!(
+            event,
+            Some(MidiEvent::NoteOn { channel: 3, note: 60, velocity }) if velocity == 1.0
+        ));
+    }
+
+    #[test]
+    fn silent_note_on_is_a_note_off() {
+        let event = MidiEvent::parse(&[0x90, 64, 0]);
+        assert!(matches!(
+            event,
+            Some(MidiEvent::NoteOff {
+                channel: 0,
+                note: 64
+            })
+        ));
+    }
+
+    #[test]
+    fn note_off_ignores_release_velocity() {
+        let event = MidiEvent::parse(&[0x89, 36, 64]);
+        assert!(matches!(
+            event,
+            Some(MidiEvent::NoteOff {
+                channel: 9,
+                note: 36
+            })
+        ));
+    }
+
+    #[test]
+    fn other_messages_are_ignored() {
+        // Control change, program change, clock and a truncated note on
+        for message in [&[0xb0, 7, 100][..], &[0xc0, 5], &[0xf8], &[0x90], &[]] {
+            assert!(MidiEvent::parse(message).is_none(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn note_on_without_velocity_is_ignored() {
+        assert!(MidiEvent::parse(&[0x90, 60]).is_none());
+    }
+}
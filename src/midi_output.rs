@@ -0,0 +1,283 @@
+use crate::mixer::SAMPLE_RATE;
+use crate::sync::SONG_POSITION;
+use log::warn;
+use midir::{MidiOutput, MidiOutputConnection};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CLIENT_NAME: &str = "devtunes";
+/// Port other programs can connect to, e.g. with `aconnect`
+pub const VIRTUAL_PORT: &str = "devtunes out";
+
+const QUEUE_SIZE: usize = 1024;
+// Note offs the mixer can have waiting at once; past that they go out early
+const MAX_PENDING: usize = 128;
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+// How fast the send latency is let down again after a late batch, in
+// seconds per second
+const LATENCY_DECAY: f64 = 0.002;
+
+/// Raw messages on their way to the sender thread, stamped with the
+/// mixer sample they belong on
+pub type MidiQueue = Producer<(u64, [u8; 3])>;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const ALL_NOTES_OFF: u8 = 123;
+
+/// Names of the output ports there are to pick from, the virtual port
+/// first where the platform has one.
+pub fn ports() -> Result<Vec<String>, Box<dyn Error>> {
+    let output = MidiOutput::new(CLIENT_NAME)?;
+    let mut ports = Vec::new();
+    if cfg!(unix) {
+        ports.push(VIRTUAL_PORT.to_string());
+    }
+    for port in output.ports() {
+        let name = output.port_name(&port)?;
+        // Don't loop back into our own input
+        if !name.contains(CLIENT_NAME) {
+            ports.push(name);
+        }
+    }
+    Ok(ports)
+}
+
+/// An open output port. Messages are queued up by the mixer and sent from
+/// a thread of its own, since sending can block.
+pub struct MidiOut {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MidiOut {
+    /// Opens a port by name, returning the queue for the mixer to fill.
+    pub fn connect(name: &str) -> Result<(Self, MidiQueue), Box<dyn Error>> {
+        let output = MidiOutput::new(CLIENT_NAME)?;
+        let connection = if name == VIRTUAL_PORT {
+            virtual_port(output)?
+        } else {
+            let port = output
+                .ports()
+                .into_iter()
+                .find(|port| output.port_name(port).is_ok_and(|n| n == name))
+                .ok_or_else(|| format!("MIDI output {} is gone", name))?;
+            output.connect(&port, VIRTUAL_PORT)?
+        };
+
+        let (producer, consumer) = RingBuffer::new(QUEUE_SIZE);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = Arc::clone(&running);
+            thread::Builder::new()
+                .name("devtunes-midi-out".to_string())
+                .spawn(move || send_messages(connection, consumer, &running))?
+        };
+
+        Ok((
+            Self {
+                running,
+                thread: Some(thread),
+            },
+            producer,
+        ))
+    }
+}
+
+impl Drop for MidiOut {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn virtual_port(output: MidiOutput) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    Ok(output.create_virtual(VIRTUAL_PORT)?)
+}
+
+#[cfg(not(unix))]
+fn virtual_port(_output: MidiOutput) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    Err("Virtual MIDI ports aren't supported on this platform".into())
+}
+
+fn message_length(status: u8) -> usize {
+    match status {
+        SONG_POSITION => 3,
+        // Real-time messages are a single byte
+        0xF8.. => 1,
+        _ if status & 0xf0 == PROGRAM_CHANGE => 2,
+        _ => 3,
+    }
+}
+
+// The mixer renders audio in bursts ahead of the output, so messages
+// arrive bunched up. Each one is held back until its sample's time plus
+// the latest any message has arrived so far, which spaces them out as
+// evenly as they were played. MIDI clock relies on this.
+fn send_messages(
+    mut connection: MidiOutputConnection,
+    mut messages: Consumer<(u64, [u8; 3])>,
+    running: &AtomicBool,
+) {
+    let start = Instant::now();
+    let mut latency: Option<f64> = None;
+    let mut last_poll = 0.0;
+    while running.load(Ordering::Relaxed) {
+        let now = start.elapsed().as_secs_f64();
+        // Let the latency down slowly, so one stall doesn't delay
+        // everything after it for good
+        if let Some(latency) = &mut latency {
+            *latency -= (now - last_poll) * LATENCY_DECAY;
+        }
+        last_poll = now;
+
+        while let Ok(&(at, message)) = messages.peek() {
+            let time = at as f64 / SAMPLE_RATE as f64;
+            let lead = *latency.insert(latency.map_or(now - time, |l| l.max(now - time)));
+            if time + lead > now {
+                break;
+            }
+            let _ = messages.pop();
+            if let Err(e) = connection.send(&message[..message_length(message[0])]) {
+                warn!("Failed to send MIDI: {}", e);
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // Nothing should be left hanging on the synth once we let go
+    for channel in 0..16 {
+        let _ = connection.send(&[CONTROL_CHANGE | channel, ALL_NOTES_OFF, 0]);
+    }
+}
+
+/// The mixer's end of the output: queues messages for the sender thread
+/// and times note offs against the sample clock. Never allocates, so it's
+/// safe on the audio thread.
+pub struct MidiSender {
+    output: Option<MidiQueue>,
+    /// Leaves the internal synth silent while an output is connected
+    pub only: bool,
+    // Due sample, channel and note
+    pending: Vec<(u64, u8, u8)>,
+    // Earliest of the pending offs
+    next_due: u64,
+    // Mixer sample messages are stamped with
+    clock: u64,
+}
+
+impl MidiSender {
+    pub fn new() -> Self {
+        Self {
+            output: None,
+            only: false,
+            pending: Vec::with_capacity(MAX_PENDING),
+            next_due: u64::MAX,
+            clock: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// True when notes should only go out over MIDI.
+    pub fn replaces_synth(&self) -> bool {
+        self.only && self.is_connected()
+    }
+
+    /// Swaps the output port, ending every note still waiting on an off.
+    /// Returns the old port's queue, which the mixer hands back to be freed.
+    pub fn set_output(&mut self, output: Option<MidiQueue>) -> Option<MidiQueue> {
+        self.flush();
+        std::mem::replace(&mut self.output, output)
+    }
+
+    fn send(&mut self, message: [u8; 3]) {
+        if let Some(output) = &mut self.output {
+            // A full queue means the port has stalled; dropping beats blocking
+            let _ = output.push((self.clock, message));
+        }
+    }
+
+    /// Starts a note, velocity from 0 to 1. With a hold the off is sent
+    /// that many samples later, otherwise it waits for `note_off`.
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: f32, hold: Option<u64>) {
+        if !self.is_connected() {
+            return;
+        }
+        let velocity = (velocity * 127.0).round().clamp(1.0, 127.0) as u8;
+        self.send([NOTE_ON | channel, note, velocity]);
+        if let Some(hold) = hold {
+            if self.pending.len() == MAX_PENDING {
+                self.send([NOTE_OFF | channel, note, 0]);
+            } else {
+                let due = self.clock + hold;
+                self.pending.push((due, channel, note));
+                self.next_due = self.next_due.min(due);
+            }
+        }
+    }
+
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        self.send([NOTE_OFF | channel, note, 0]);
+    }
+
+    pub fn program_change(&mut self, channel: u8, program: u8) {
+        self.send([PROGRAM_CHANGE | channel, program, 0]);
+    }
+
+    /// Clock, start, stop and the other single-byte real-time messages.
+    pub fn real_time(&mut self, status: u8) {
+        self.send([status, 0, 0]);
+    }
+
+    /// Song position pointer, in sixteenth notes.
+    pub fn song_position(&mut self, sixteenths: u64) {
+        let position = sixteenths.min(0x3fff);
+        self.send([
+            SONG_POSITION,
+            (position & 0x7f) as u8,
+            (position >> 7) as u8,
+        ]);
+    }
+
+    /// Moves on to the mixer's sample `now`, sending the note offs that
+    /// have come due.
+    pub fn advance(&mut self, now: u64) {
+        self.clock = now;
+        if now < self.next_due {
+            return;
+        }
+        self.next_due = u64::MAX;
+        let mut i = 0;
+        while i < self.pending.len() {
+            let (due, channel, note) = self.pending[i];
+            if due <= now {
+                self.pending.swap_remove(i);
+                self.note_off(channel, note);
+            } else {
+                self.next_due = self.next_due.min(due);
+                i += 1;
+            }
+        }
+    }
+
+    /// Ends every held note straight away.
+    pub fn flush(&mut self) {
+        while let Some((_, channel, note)) = self.pending.pop() {
+            self.note_off(channel, note);
+        }
+        self.next_due = u64::MAX;
+    }
+}
@@ -0,0 +1,748 @@
+use crate::audio::{AudioCommand, PlaybackStatus, SequenceEvent, SequenceSound};
+use crate::drum_synth::{ClickVoice, DrumVoice};
+use crate::drums::DrumSound;
+use crate::envelope::{Adsr, Envelope};
+use crate::instrument::{CustomWaveform, InstrumentType};
+use crate::keyboard;
+use crate::kit::{KitSounds, SampleVoice};
+use crate::midi_file::{self, DRUM_CHANNEL};
+use crate::midi_output::{MidiQueue, MidiSender};
+use crate::sequencer::{DrumMachine, PatternHits, STEP_TICKS};
+use crate::sync::{self, SyncMessage, SyncMode, PULSE_TICKS};
+use crate::transport::{self, Clock, TimeSignature, TransportState};
+use rodio::Source;
+use rtrb::{Consumer, Producer};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const MAX_VOICES: usize = 16;
+// Stolen voices fade out over this many samples (~5 ms) rather than
+// stopping dead, which would click
+const STEAL_FADE: u32 = SAMPLE_RATE / 200;
+// Room for stolen voices to fade in on top of the ones still playing
+const VOICE_SLOTS: usize = MAX_VOICES * 2;
+
+// Pending commands are picked up at the start of every block (~1.5 ms)
+pub const CONTROL_BLOCK: u64 = 64;
+
+// Headroom so a handful of overlapping voices doesn't clip straight away
+const MASTER_GAIN: f32 = 0.8;
+const NOTE_GAIN: f32 = 0.20;
+const DRUM_GAIN: f32 = 0.3;
+const CLICK_GAIN: f32 = 0.5;
+
+// Drum hits have no length of their own, so MIDI ones get a short note
+const DRUM_HOLD: u64 = SAMPLE_RATE as u64 / 20;
+
+/// A sound the mixer can play. Sustaining voices fade out on `release`,
+/// one-shots just ignore it and run to completion. Voices are held inline
+/// rather than boxed, so starting one never allocates on the audio thread.
+enum VoiceSource {
+    Note(Adsr<CustomWaveform>),
+    Drum(DrumVoice),
+    Sample(SampleVoice),
+    Click(ClickVoice),
+}
+
+impl VoiceSource {
+    fn release(&mut self) {
+        if let VoiceSource::Note(note) = self {
+            note.release();
+        }
+    }
+
+    /// True for a note that's been let go and is fading out.
+    fn is_releasing(&self) -> bool {
+        matches!(self, VoiceSource::Note(note) if note.is_releasing())
+    }
+}
+
+impl Iterator for VoiceSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match self {
+            VoiceSource::Note(note) => note.next(),
+            VoiceSource::Drum(drum) => drum.next(),
+            VoiceSource::Sample(sample) => sample.next(),
+            VoiceSource::Click(click) => click.next(),
+        }
+    }
+}
+
+/// Buffers the mixer has finished with. They go back to the engine to be
+/// freed, since freeing can take a lock on the audio thread.
+// Nothing reads them; they're only carried across to be dropped
+#[allow(dead_code)]
+pub enum Retired {
+    Events(Vec<SequenceEvent>),
+    Kit(KitSounds),
+    Drums(Vec<PatternHits>),
+    MidiOutput(MidiQueue),
+    SyncInput(Consumer<SyncMessage>),
+}
+
+pub fn to_samples(duration: Duration) -> u64 {
+    (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64
+}
+
+pub fn to_duration(samples: u64) -> Duration {
+    Duration::from_secs_f64(samples as f64 / SAMPLE_RATE as f64)
+}
+
+// The UI keeps every kit it has loaded, so a sample voice never holds the
+// last reference to its data
+fn drum_source(drum: DrumSound, kit: &KitSounds) -> Option<VoiceSource> {
+    match drum {
+        DrumSound::Sample(index) => kit
+            .get(index)
+            .map(|(data, _)| VoiceSource::Sample(SampleVoice::new(Arc::clone(data)))),
+        _ => Some(VoiceSource::Drum(DrumVoice::new(drum, SAMPLE_RATE))),
+    }
+}
+
+/// Kit samples go out over MIDI as the note the kit gave them.
+fn drum_note(drum: DrumSound, kit: &KitSounds) -> u8 {
+    match drum {
+        DrumSound::Sample(index) => kit
+            .get(index)
+            .map_or_else(|| midi_file::drum_note(drum, None), |&(_, note)| note),
+        _ => midi_file::drum_note(drum, None),
+    }
+}
+
+struct Voice {
+    source: VoiceSource,
+    gain: f32,
+    note: Option<u8>,
+    started_at: u64,
+    release_at: Option<u64>,
+    // Samples left of the fade once the voice has been stolen
+    fade: Option<u32>,
+}
+
+struct VoicePool {
+    // Never holds more than VOICE_SLOTS, so pushing never reallocates
+    voices: Vec<Voice>,
+    clock: u64,
+}
+
+impl VoicePool {
+    fn new() -> Self {
+        Self {
+            voices: Vec::with_capacity(VOICE_SLOTS),
+            clock: 0,
+        }
+    }
+
+    fn trigger(&mut self, source: VoiceSource, gain: f32, note: Option<u8>, hold: Option<u64>) {
+        let playing = self.voices.iter().filter(|v| v.fade.is_none()).count();
+        if playing >= MAX_VOICES {
+            // Pool is full: steal the oldest voice that's already fading
+            // out, or failing that the oldest one of all
+            if let Some(stolen) = self
+                .voices
+                .iter_mut()
+                .filter(|v| v.fade.is_none())
+                .min_by_key(|v| (!v.source.is_releasing(), v.started_at))
+            {
+                stolen.fade = Some(STEAL_FADE);
+                stolen.note = None;
+            }
+        }
+        if self.voices.len() >= VOICE_SLOTS {
+            // Too many steals at once to fade them all: cut the stolen
+            // voice that was nearest to silent
+            if let Some(quietest) = self
+                .voices
+                .iter()
+                .enumerate()
+                .filter_map(|(i, v)| v.fade.map(|fade| (i, fade)))
+                .min_by_key(|&(_, fade)| fade)
+                .map(|(i, _)| i)
+            {
+                self.voices.swap_remove(quietest);
+            }
+        }
+
+        self.voices.push(Voice {
+            source,
+            gain,
+            note,
+            started_at: self.clock,
+            release_at: hold.map(|hold| self.clock + hold),
+            fade: None,
+        });
+    }
+
+    fn release(&mut self, note: u8) {
+        for voice in self.voices.iter_mut().filter(|v| v.note == Some(note)) {
+            voice.source.release();
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let clock = self.clock;
+        let mut mix = 0.0;
+        self.voices.retain_mut(|voice| {
+            if voice.release_at == Some(clock) {
+                voice.source.release();
+            }
+            let fade = match &mut voice.fade {
+                Some(0) => return false,
+                Some(left) => {
+                    *left -= 1;
+                    *left as f32 / STEAL_FADE as f32
+                }
+                None => 1.0,
+            };
+            match voice.source.next() {
+                Some(sample) => {
+                    mix += sample * voice.gain * fade;
+                    true
+                }
+                None => false,
+            }
+        });
+        self.clock = self.clock.wrapping_add(1);
+
+        (mix * MASTER_GAIN).clamp(-1.0, 1.0)
+    }
+}
+
+struct Sequence {
+    events: Vec<SequenceEvent>,
+    next_event: usize,
+    position: u64,
+    length: u64,
+    looping: bool,
+    // Part of a sample that nudges have yet to move the position by
+    drift: f64,
+}
+
+impl Sequence {
+    fn new(events: Vec<SequenceEvent>, length: Duration, looping: bool) -> Self {
+        Self {
+            events,
+            next_event: 0,
+            position: 0,
+            length: to_samples(length),
+            looping,
+            drift: 0.0,
+        }
+    }
+
+    /// Swaps in new events without losing the place in the sequence. A
+    /// new length (after a tempo change) keeps the playhead proportional.
+    /// Returns the events it replaced.
+    fn replace(&mut self, events: Vec<SequenceEvent>, length: Duration) -> Vec<SequenceEvent> {
+        let length = to_samples(length);
+        if self.length > 0 {
+            self.position = (self.position as u128 * length as u128 / self.length as u128) as u64;
+        }
+        self.length = length;
+        self.next_event = events.partition_point(|event| to_samples(event.at) < self.position);
+        std::mem::replace(&mut self.events, events)
+    }
+
+    fn rewind(&mut self) {
+        self.position = 0;
+        self.next_event = 0;
+    }
+
+    /// Moves the playhead by `samples` either way. Events jumped over
+    /// still play, and ones already played don't play again.
+    fn nudge(&mut self, samples: f64) {
+        self.drift += samples;
+        let whole = self.drift.round();
+        self.drift -= whole;
+        self.position = self.position.saturating_add_signed(whole as i64);
+    }
+
+    /// Pops the next event that is due at the current position.
+    fn next_due(&mut self) -> Option<SequenceEvent> {
+        let event = self
+            .events
+            .get(self.next_event)
+            .filter(|event| to_samples(event.at) <= self.position)
+            .copied()?;
+        self.next_event += 1;
+        Some(event)
+    }
+
+    /// Moves on by one sample. Returns false once a one-shot sequence ends.
+    fn tick(&mut self) -> bool {
+        self.position += 1;
+        if self.position >= self.length {
+            if !self.looping || self.length == 0 {
+                return false;
+            }
+            self.rewind();
+        }
+        true
+    }
+}
+
+/// Endless source that sums every active voice into a single output stream.
+///
+/// Runs on the output device's callback thread and is driven entirely by the
+/// commands it pops off the engine's queue, so it never waits on the UI.
+/// Nor does it allocate or free: voices live in a pool sized up front, and
+/// buffers it replaces go back to the engine in `Retired`.
+pub struct Mixer {
+    voices: VoicePool,
+    commands: Consumer<AudioCommand>,
+    retired: Producer<Retired>,
+    status: Arc<PlaybackStatus>,
+    instrument: InstrumentType,
+    envelopes: [Envelope; 4],
+    lofi: bool,
+    kit: KitSounds,
+    sequence: Option<Sequence>,
+    loops: Option<Sequence>,
+    clock: Clock,
+    time_signature: TimeSignature,
+    metronome: bool,
+    metronome_volume: f32,
+    // Clicks are forced on until this tick while counting in
+    count_in_until: u64,
+    drums: DrumMachine,
+    midi: MidiSender,
+    sync: SyncMode,
+    sync_input: Option<Consumer<SyncMessage>>,
+    // Pulses of external clock since it started, and the sample the last
+    // one came in on
+    pulses: u64,
+    last_pulse: Option<u64>,
+    // Start and continue wait for the next pulse before the transport runs
+    awaiting_pulse: bool,
+}
+
+impl Mixer {
+    pub fn new(
+        commands: Consumer<AudioCommand>,
+        retired: Producer<Retired>,
+        status: Arc<PlaybackStatus>,
+    ) -> Self {
+        Self {
+            voices: VoicePool::new(),
+            commands,
+            retired,
+            status,
+            instrument: InstrumentType::Sine,
+            envelopes: [Envelope::default(); 4],
+            lofi: false,
+            kit: Vec::new(),
+            sequence: None,
+            loops: None,
+            clock: Clock::new(),
+            time_signature: TimeSignature::default(),
+            metronome: false,
+            metronome_volume: 0.5,
+            count_in_until: 0,
+            drums: DrumMachine::new(),
+            midi: MidiSender::new(),
+            sync: SyncMode::Internal,
+            sync_input: None,
+            pulses: 0,
+            last_pulse: None,
+            awaiting_pulse: false,
+        }
+    }
+
+    /// True once nothing is sounding and no sequence is queued up.
+    pub fn is_idle(&self) -> bool {
+        self.sequence.is_none() && self.loops.is_none() && self.voices.voices.is_empty()
+    }
+
+    fn handle_command(&mut self, command: AudioCommand) {
+        match command {
+            AudioCommand::NoteOn(note, velocity) => {
+                self.note_on(note, self.instrument, velocity, None)
+            }
+            AudioCommand::NoteOff(note) => {
+                self.voices.release(note);
+                self.midi.note_off(0, note);
+            }
+            AudioCommand::DrumHit(drum, velocity) => self.drum_hit(drum, velocity),
+            AudioCommand::SetInstrument(instrument) => {
+                self.instrument = instrument;
+                self.midi.program_change(0, midi_file::program(instrument));
+            }
+            AudioCommand::SetEnvelope(instrument, envelope) => {
+                self.envelopes[instrument as usize] = envelope;
+            }
+            AudioCommand::SetLofi(lofi) => self.lofi = lofi,
+            AudioCommand::SetKit(kit) => {
+                let old = std::mem::replace(&mut self.kit, kit);
+                self.retire(Retired::Kit(old));
+            }
+            AudioCommand::PlaySequence {
+                events,
+                length,
+                looping,
+            } => {
+                self.end_sequence();
+                self.sequence = Some(Sequence::new(events, length, looping));
+                self.play();
+            }
+            AudioCommand::StopSequence => {
+                self.end_sequence();
+                self.midi.flush();
+            }
+            AudioCommand::PlayLoops { events, length } => match &mut self.loops {
+                Some(loops) => {
+                    let old = loops.replace(events, length);
+                    self.retire(Retired::Events(old));
+                }
+                None => {
+                    self.loops = Some(Sequence::new(events, length, true));
+                    self.play();
+                }
+            },
+            AudioCommand::StopLoops => {
+                self.end_loops();
+                self.midi.flush();
+            }
+            AudioCommand::SetTempo(bpm) => self.clock.bpm = bpm,
+            AudioCommand::SetTimeSignature(time_signature) => {
+                self.time_signature = time_signature;
+            }
+            AudioCommand::SetMetronome { enabled, volume } => {
+                self.metronome = enabled;
+                self.metronome_volume = volume;
+            }
+            AudioCommand::CountIn(until) => {
+                self.count_in_until = until;
+                self.play();
+            }
+            AudioCommand::SetDrums {
+                patterns,
+                looping,
+                at_bar,
+            } => {
+                let queue = at_bar && self.clock.is_playing();
+                for old in self.drums.set(patterns, looping, queue, at_bar) {
+                    self.retire(Retired::Drums(old));
+                }
+            }
+            AudioCommand::TransportPlay => self.play(),
+            AudioCommand::TransportPause => self.pause(),
+            AudioCommand::TransportStop => self.stop(),
+            AudioCommand::SetMidiOutput(output) => {
+                if let Some(old) = self.midi.set_output(output) {
+                    self.retire(Retired::MidiOutput(old));
+                }
+                // Bring the synth on the other end up to date
+                self.midi
+                    .program_change(0, midi_file::program(self.instrument));
+            }
+            AudioCommand::SetMidiOnly(only) => self.midi.only = only,
+            AudioCommand::SetSync(mode) => {
+                self.sync = mode;
+                self.awaiting_pulse = false;
+            }
+            AudioCommand::SetSyncInput(input) => {
+                if let Some(old) = self.sync_input.replace(input) {
+                    self.retire(Retired::SyncInput(old));
+                }
+            }
+        }
+    }
+
+    /// Hands a buffer back to the engine to be freed.
+    fn retire(&mut self, buffer: Retired) {
+        // The queue only fills up if the engine has stopped emptying it, and
+        // then freeing the buffer here is all there is left to do
+        let _ = self.retired.push(buffer);
+    }
+
+    fn end_sequence(&mut self) {
+        if let Some(sequence) = self.sequence.take() {
+            self.retire(Retired::Events(sequence.events));
+        }
+    }
+
+    fn end_loops(&mut self) {
+        if let Some(loops) = self.loops.take() {
+            self.retire(Retired::Events(loops.events));
+        }
+    }
+
+    fn start_queued_drums(&mut self) {
+        if let Some(old) = self.drums.start_queued() {
+            self.retire(Retired::Drums(old));
+        }
+    }
+
+    /// Starts or resumes the transport, telling anything following our
+    /// clock to do the same.
+    fn play(&mut self) {
+        if !self.clock.is_playing() && self.sync == SyncMode::Master {
+            if self.clock.ticks() == 0 {
+                self.midi.real_time(sync::START);
+            } else {
+                self.midi
+                    .song_position(self.clock.ticks() / (transport::PPQN / 4));
+                self.midi.real_time(sync::CONTINUE);
+            }
+        }
+        self.clock.state = TransportState::Playing;
+    }
+
+    fn pause(&mut self) {
+        if self.clock.is_playing() {
+            if self.sync == SyncMode::Master {
+                self.midi.real_time(sync::STOP);
+            }
+            self.clock.state = TransportState::Paused;
+        }
+    }
+
+    fn stop(&mut self) {
+        self.pause();
+        self.clock.stop();
+        self.count_in_until = 0;
+        self.start_queued_drums();
+        self.drums.rewind();
+        self.end_sequence();
+        if let Some(loops) = &mut self.loops {
+            loops.rewind();
+        }
+        self.midi.flush();
+    }
+
+    /// Runs the transport from incoming MIDI clock while following it.
+    /// Tempo comes from the measured pulse rate, and each pulse nudges the
+    /// position so loops and drums stay locked to the source. The loop
+    /// station runs on samples rather than ticks, so it gets the same nudge.
+    fn follow_clock(&mut self) {
+        while let Some(message) = self.sync_input.as_mut().and_then(|input| input.pop().ok()) {
+            if self.sync != SyncMode::Slave {
+                continue;
+            }
+            match message {
+                SyncMessage::Clock(bpm) => {
+                    if let Some(bpm) = bpm {
+                        self.clock.bpm = bpm;
+                    }
+                    self.last_pulse = Some(self.voices.clock);
+                    if self.awaiting_pulse {
+                        self.awaiting_pulse = false;
+                        self.play();
+                    } else if self.clock.is_playing() {
+                        self.pulses += 1;
+                        let moved = self.clock.follow(self.pulses * PULSE_TICKS);
+                        if let Some(loops) = &mut self.loops {
+                            loops.nudge(moved);
+                        }
+                    }
+                }
+                SyncMessage::Start => {
+                    self.stop();
+                    self.pulses = 0;
+                    self.awaiting_pulse = true;
+                }
+                SyncMessage::Continue => {
+                    self.pulses = self.clock.ticks() / PULSE_TICKS;
+                    self.awaiting_pulse = true;
+                }
+                SyncMessage::Stop => {
+                    self.awaiting_pulse = false;
+                    self.pause();
+                }
+            }
+        }
+    }
+
+    /// Tempo of the clock being followed, while it's still coming in.
+    fn sync_bpm(&self) -> Option<f32> {
+        let timeout = to_samples(sync::CLOCK_TIMEOUT);
+        self.last_pulse
+            .filter(|&last| self.sync == SyncMode::Slave && self.voices.clock - last < timeout)
+            .map(|_| self.clock.bpm)
+    }
+
+    /// Live notes are tagged with their note so a NoteOff can release them;
+    /// sequenced notes carry a hold time instead.
+    fn note_on(&mut self, note: u8, instrument: InstrumentType, velocity: f32, hold: Option<u64>) {
+        if self.midi.is_connected() {
+            if hold.is_none() {
+                self.midi.note_off(0, note);
+            }
+            self.midi.note_on(0, note, velocity, hold);
+            if self.midi.replaces_synth() {
+                return;
+            }
+        }
+
+        let frequency = keyboard::note_frequency(note);
+        let envelope = self.envelopes[instrument as usize];
+        let source = VoiceSource::Note(Adsr::new(
+            CustomWaveform::new(frequency, instrument).with_lofi(self.lofi),
+            envelope,
+            SAMPLE_RATE,
+        ));
+
+        let tag = if hold.is_none() {
+            self.voices.release(note);
+            Some(note)
+        } else {
+            None
+        };
+        self.voices.trigger(source, NOTE_GAIN * velocity, tag, hold);
+    }
+
+    fn drum_hit(&mut self, drum: DrumSound, velocity: f32) {
+        self.midi.note_on(
+            DRUM_CHANNEL,
+            drum_note(drum, &self.kit),
+            velocity,
+            Some(DRUM_HOLD),
+        );
+        if self.midi.replaces_synth() {
+            return;
+        }
+        if let Some(source) = drum_source(drum, &self.kit) {
+            self.voices
+                .trigger(source, DRUM_GAIN * velocity, None, None);
+        }
+    }
+
+    fn play_event(&mut self, event: SequenceEvent) {
+        match event.sound {
+            SequenceSound::Note {
+                note,
+                duration,
+                instrument,
+            } => {
+                let instrument = instrument.unwrap_or(self.instrument);
+                self.note_on(note, instrument, 1.0, Some(to_samples(duration)));
+            }
+            SequenceSound::Drum(drum) => self.drum_hit(drum, 1.0),
+        }
+    }
+
+    fn advance_sequences(&mut self) {
+        if !self.clock.is_playing() {
+            return;
+        }
+        if let Some(tick) = self.clock.advance() {
+            if self.sync == SyncMode::Master && tick.is_multiple_of(PULSE_TICKS) {
+                self.midi.real_time(sync::CLOCK);
+            }
+            self.click(tick);
+            self.play_step(tick);
+        }
+
+        while let Some(event) = self.sequence.as_mut().and_then(Sequence::next_due) {
+            self.play_event(event);
+        }
+        if self
+            .sequence
+            .as_mut()
+            .is_some_and(|sequence| !sequence.tick())
+        {
+            self.end_sequence();
+        }
+
+        while let Some(event) = self.loops.as_mut().and_then(Sequence::next_due) {
+            self.play_event(event);
+        }
+        if self.loops.as_mut().is_some_and(|loops| !loops.tick()) {
+            self.end_loops();
+        }
+    }
+
+    fn click(&mut self, tick: u64) {
+        let counting_in = tick < self.count_in_until;
+        if !(self.metronome || counting_in)
+            || !tick.is_multiple_of(self.time_signature.ticks_per_beat())
+        {
+            return;
+        }
+        let accent = tick.is_multiple_of(self.time_signature.ticks_per_bar());
+        self.voices.trigger(
+            VoiceSource::Click(ClickVoice::new(accent, SAMPLE_RATE)),
+            CLICK_GAIN * self.metronome_volume,
+            None,
+            None,
+        );
+    }
+
+    fn play_step(&mut self, tick: u64) {
+        if !tick.is_multiple_of(STEP_TICKS) {
+            return;
+        }
+        if tick.is_multiple_of(self.time_signature.ticks_per_bar()) {
+            self.start_queued_drums();
+        }
+        for &(drum, velocity) in self.drums.advance() {
+            self.midi.note_on(
+                DRUM_CHANNEL,
+                drum_note(drum, &self.kit),
+                velocity,
+                Some(DRUM_HOLD),
+            );
+            if self.midi.replaces_synth() {
+                continue;
+            }
+            if let Some(source) = drum_source(drum, &self.kit) {
+                self.voices
+                    .trigger(source, DRUM_GAIN * velocity, None, None);
+            }
+        }
+    }
+
+    fn publish_status(&self) {
+        match &self.sequence {
+            Some(sequence) => self.status.update(true, sequence.position, sequence.length),
+            None => self.status.update(false, 0, 0),
+        }
+        match &self.loops {
+            Some(loops) => self.status.update_loops(loops.position, loops.length),
+            None => self.status.update_loops(0, 0),
+        }
+        self.status
+            .update_transport(self.clock.state, self.clock.ticks());
+        self.status.update_drums(self.drums.position);
+        self.status.update_sync(self.sync_bpm());
+    }
+}
+
+impl Iterator for Mixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.voices.clock.is_multiple_of(CONTROL_BLOCK) {
+            while let Ok(command) = self.commands.pop() {
+                self.handle_command(command);
+            }
+            self.follow_clock();
+            self.publish_status();
+        }
+
+        self.midi.advance(self.voices.clock);
+        self.advance_sequences();
+        Some(self.voices.next_sample())
+    }
+}
+
+impl Source for Mixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,247 @@
+use crate::envelope::Envelope;
+use crate::gemini_player::MelodyPattern;
+use crate::instrument::InstrumentType;
+use crate::looper::Loop;
+use crate::recorder::Take;
+use crate::sequencer::{PatternBank, Velocity};
+use crate::transport::TimeSignature;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_PATH: &str = "devtunes-project.json";
+
+// Upgrades from each older format, oldest first: the first entry turns a
+// version 1 project into version 2, and so on. Changing the format means
+// appending a step here, which also bumps `VERSION`.
+const MIGRATIONS: &[fn(&mut Value)] = &[];
+
+pub const VERSION: u64 = MIGRATIONS.len() as u64 + 1;
+
+/// Everything needed to pick a session back up, saved as versioned JSON.
+#[derive(Serialize, Deserialize)]
+pub struct Project {
+    pub version: u64,
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    pub instrument: InstrumentType,
+    pub envelopes: HashMap<InstrumentType, Envelope>,
+    pub lofi: bool,
+    pub takes: Vec<Take>,
+    pub current_take: Option<usize>,
+    pub loops: Vec<Loop>,
+    /// Loop station length and the tempo it was recorded at
+    pub loop_length: Option<Duration>,
+    pub loop_bpm: f32,
+    pub drums: PatternBank,
+    pub song_mode: bool,
+    /// Last melody the AI came up with
+    pub melody: Option<MelodyPattern>,
+}
+
+impl Project {
+    /// True when there is nothing in the project worth getting back.
+    pub fn is_empty(&self) -> bool {
+        self.takes.is_empty()
+            && self.loops.is_empty()
+            && self.melody.is_none()
+            && self.drums.patterns.iter().all(|pattern| {
+                pattern
+                    .rows
+                    .values()
+                    .flatten()
+                    .all(|&velocity| velocity == Velocity::Off)
+            })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Reads a project, upgrading it first if an older version saved it.
+    pub fn load(path: &Path) -> Result<Project, Box<dyn Error>> {
+        let mut project: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        upgrade(&mut project, MIGRATIONS)?;
+        Ok(serde_json::from_value(project)?)
+    }
+}
+
+// Runs the steps from the project's version on, leaving it at the version
+// after the last step
+fn upgrade(project: &mut Value, migrations: &[fn(&mut Value)]) -> Result<(), Box<dyn Error>> {
+    let latest = migrations.len() as u64 + 1;
+    let version = project
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or("Not a devtunes project")?;
+    if version == 0 || version > latest {
+        return Err(format!(
+            "Project version {} is not supported (up to {})",
+            version, latest
+        )
+        .into());
+    }
+
+    for migrate in &migrations[version as usize - 1..] {
+        migrate(project);
+    }
+    project["version"] = latest.into();
+    Ok(())
+}
+
+fn autosave_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("devtunes").join("autosave.json"))
+}
+
+/// Saves the session under the XDG data directory. The file is written
+/// alongside and renamed over the last autosave, so a crash mid-write
+/// can't lose it.
+pub fn autosave(project: &Project) -> Result<(), Box<dyn Error>> {
+    let path = autosave_path().ok_or("No data directory available")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("json.tmp");
+    project.save(&partial)?;
+    fs::rename(partial, path)?;
+    Ok(())
+}
+
+/// The last autosaved session, unless there was nothing in it.
+pub fn load_autosave() -> Option<Project> {
+    autosave_path()
+        .and_then(|path| Project::load(&path).ok())
+        .filter(|project| !project.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::Note;
+    use crate::sequencer::Velocity;
+
+    // A file of its own per test, so they can run side by side
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("devtunes-{}-{}.json", name, std::process::id()))
+    }
+
+    fn project() -> Project {
+        let mut drums = PatternBank::new();
+        drums.patterns[0].set_step('z', 0, Velocity::Accent);
+        drums.patterns[2].set_step('x', 4, Velocity::Soft);
+        drums.song = vec![0, 2, 0];
+        let mut envelopes = HashMap::new();
+        envelopes.insert(
+            InstrumentType::Square,
+            Envelope {
+                attack: 0.05,
+                ..Envelope::default()
+            },
+        );
+        Project {
+            version: VERSION,
+            bpm: 96.0,
+            time_signature: TimeSignature { beats: 3, unit: 4 },
+            instrument: InstrumentType::Square,
+            envelopes,
+            lofi: true,
+            takes: vec![Take {
+                notes: vec![Note::Key {
+                    key: 'a',
+                    timestamp: Duration::from_millis(250),
+                    duration: Duration::from_millis(400),
+                    instrument: None,
+                    shift: -12,
+                }],
+                length: Duration::from_secs(2),
+                bpm: Some(96.0),
+                kit: None,
+            }],
+            current_take: Some(0),
+            loops: Vec::new(),
+            loop_length: None,
+            loop_bpm: 96.0,
+            drums,
+            song_mode: true,
+            melody: None,
+        }
+    }
+
+    fn load_json(name: &str, json: &str) -> Result<Project, Box<dyn Error>> {
+        let path = temp_path(name);
+        fs::write(&path, json).unwrap();
+        let project = Project::load(&path);
+        let _ = fs::remove_file(path);
+        project
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+        let saved = project();
+        saved.save(&path).unwrap();
+        let loaded = Project::load(&path);
+        let _ = fs::remove_file(path);
+        let loaded = loaded.unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&saved).unwrap()
+        );
+        assert_eq!(loaded.version, VERSION);
+        assert_eq!(loaded.bpm, 96.0);
+        assert!(loaded.lofi);
+        assert_eq!(loaded.envelopes[&InstrumentType::Square].attack, 0.05);
+        assert_eq!(loaded.takes[0].notes[0].pitch(), Some(57));
+        assert_eq!(loaded.drums.patterns[0].step('z', 0), Velocity::Accent);
+        assert_eq!(loaded.drums.patterns[2].step('x', 4), Velocity::Soft);
+        assert_eq!(loaded.drums.song, [0, 2, 0]);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut json = serde_json::to_value(project()).unwrap();
+        json["version"] = (VERSION + 1).into();
+        let error = load_json("newer", &json.to_string()).err().unwrap();
+        assert!(error.to_string().contains("not supported"), "{}", error);
+
+        json["version"] = 0.into();
+        assert!(load_json("zero", &json.to_string()).is_err());
+        assert!(load_json("unversioned", r#"{"bpm": 120.0}"#).is_err());
+    }
+
+    #[test]
+    fn older_versions_are_migrated_in_order() {
+        fn rename_tempo(project: &mut Value) {
+            if let Some(tempo) = project.as_object_mut().and_then(|p| p.remove("tempo")) {
+                project["bpm"] = tempo;
+            }
+        }
+        fn double_bpm(project: &mut Value) {
+            let bpm = project["bpm"].as_f64().unwrap_or_default();
+            project["bpm"] = (bpm * 2.0).into();
+        }
+        let migrations: &[fn(&mut Value)] = &[rename_tempo, double_bpm];
+
+        let mut first = serde_json::json!({"version": 1, "tempo": 60.0});
+        upgrade(&mut first, migrations).unwrap();
+        assert_eq!(first, serde_json::json!({"version": 3, "bpm": 120.0}));
+
+        // Only the steps after the saved version run
+        let mut second = serde_json::json!({"version": 2, "bpm": 60.0});
+        upgrade(&mut second, migrations).unwrap();
+        assert_eq!(second, serde_json::json!({"version": 3, "bpm": 120.0}));
+
+        let mut latest = serde_json::json!({"version": 3, "bpm": 60.0});
+        upgrade(&mut latest, migrations).unwrap();
+        assert_eq!(latest["bpm"], 60.0);
+
+        let mut newer = serde_json::json!({"version": 4, "bpm": 60.0});
+        assert!(upgrade(&mut newer, migrations).is_err());
+    }
+}
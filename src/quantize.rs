@@ -0,0 +1,209 @@
+use crate::recorder::{Note, Take};
+use crate::transport::{self, PPQN};
+use std::time::Duration;
+
+const STRENGTHS: [f32; 4] = [0.25, 0.5, 0.75, 1.0];
+const SWINGS: [f32; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grid {
+    Quarter,
+    Eighth,
+    Sixteenth,
+    EighthTriplet,
+    SixteenthTriplet,
+}
+
+impl Grid {
+    pub const ALL: [Grid; 5] = [
+        Grid::Quarter,
+        Grid::Eighth,
+        Grid::Sixteenth,
+        Grid::EighthTriplet,
+        Grid::SixteenthTriplet,
+    ];
+
+    pub fn next(&self) -> Grid {
+        let index = Self::ALL.iter().position(|g| g == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Grid::Quarter => "1/4",
+            Grid::Eighth => "1/8",
+            Grid::Sixteenth => "1/16",
+            Grid::EighthTriplet => "1/8T",
+            Grid::SixteenthTriplet => "1/16T",
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        match self {
+            Grid::Quarter => PPQN,
+            Grid::Eighth => PPQN / 2,
+            Grid::Sixteenth => PPQN / 4,
+            Grid::EighthTriplet => PPQN / 3,
+            Grid::SixteenthTriplet => PPQN / 6,
+        }
+    }
+}
+
+/// Quantize settings. Applying them produces a new take, so the raw
+/// recording is never touched and the two can be compared.
+pub struct Quantize {
+    pub enabled: bool,
+    pub grid: Grid,
+    /// How far notes move towards the grid, from 0 (not at all) to 1
+    pub strength: f32,
+    /// Delay of every other grid step; 1 is a full triplet shuffle
+    pub swing: f32,
+}
+
+impl Quantize {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            grid: Grid::Sixteenth,
+            strength: 1.0,
+            swing: 0.0,
+        }
+    }
+
+    pub fn next_strength(&mut self) {
+        self.strength = next_in(&STRENGTHS, self.strength);
+    }
+
+    pub fn next_swing(&mut self) {
+        self.swing = next_in(&SWINGS, self.swing);
+    }
+
+    /// Snaps note starts to the grid at the take's tempo (or `bpm` for
+    /// takes recorded before tempo was saved). Durations are kept.
+    pub fn apply(&self, take: &Take, bpm: f32) -> Take {
+        let bpm = take.bpm.unwrap_or(bpm);
+        let step = transport::ticks_to_duration(self.grid.ticks(), bpm);
+        let mut quantized = take.clone();
+        // Instrument changes snap too, keeping them ahead of the notes
+        // they were made for
+        for note in &mut quantized.notes {
+            let timestamp = note.timestamp();
+            let target = self.nearest(timestamp, step);
+            let offset = target.as_secs_f64() - timestamp.as_secs_f64();
+            note.set_timestamp(Duration::from_secs_f64(
+                timestamp.as_secs_f64() + offset * self.strength as f64,
+            ));
+        }
+        quantized.notes.sort_by_key(Note::timestamp);
+        quantized
+    }
+
+    fn grid_point(&self, index: u64, step: Duration) -> Duration {
+        let point = step * index as u32;
+        if index % 2 == 1 {
+            point + step.mul_f32(self.swing / 3.0)
+        } else {
+            point
+        }
+    }
+
+    fn nearest(&self, time: Duration, step: Duration) -> Duration {
+        let index = (time.as_secs_f64() / step.as_secs_f64()).round() as u64;
+        (index.saturating_sub(1)..=index + 1)
+            .map(|i| self.grid_point(i, step))
+            .min_by_key(|point| point.abs_diff(time))
+            .unwrap_or(time)
+    }
+}
+
+fn next_in(values: &[f32], current: f32) -> f32 {
+    let index = values.iter().position(|&v| v == current).unwrap_or(0);
+    values[(index + 1) % values.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drums::DrumSound;
+
+    // At 120 BPM a quarter note is 500 ms
+    const BPM: f32 = 120.0;
+
+    fn take(starts: &[u64]) -> Take {
+        Take {
+            notes: starts
+                .iter()
+                .map(|&ms| Note::Drum {
+                    drum: DrumSound::Kick,
+                    timestamp: Duration::from_millis(ms),
+                })
+                .collect(),
+            length: Duration::from_secs(2),
+            bpm: Some(BPM),
+            kit: None,
+        }
+    }
+
+    fn quantize(grid: Grid, strength: f32, swing: f32) -> Quantize {
+        Quantize {
+            enabled: true,
+            grid,
+            strength,
+            swing,
+        }
+    }
+
+    // Note starts to the nearest millisecond
+    fn starts(take: &Take) -> Vec<u64> {
+        take.notes
+            .iter()
+            .map(|note| (note.timestamp().as_secs_f64() * 1000.0).round() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn full_strength_snaps_to_the_grid() {
+        let played = take(&[10, 130, 240, 510]);
+        let sixteenths = quantize(Grid::Sixteenth, 1.0, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&sixteenths), [0, 125, 250, 500]);
+        let quarters = quantize(Grid::Quarter, 1.0, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&quarters), [0, 0, 0, 500]);
+    }
+
+    #[test]
+    fn partial_strength_moves_part_way() {
+        let played = take(&[145, 240]);
+        let half = quantize(Grid::Sixteenth, 0.5, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&half), [135, 245]);
+    }
+
+    #[test]
+    fn triplet_grids_split_the_beat_in_three() {
+        let played = take(&[160, 340, 480]);
+        let eighths = quantize(Grid::EighthTriplet, 1.0, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&eighths), [167, 333, 500]);
+        let sixteenths = quantize(Grid::SixteenthTriplet, 1.0, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&sixteenths), [167, 333, 500]);
+        let late = quantize(Grid::SixteenthTriplet, 1.0, 0.0).apply(&take(&[90]), BPM);
+        assert_eq!(starts(&late), [83]);
+    }
+
+    #[test]
+    fn swing_only_delays_off_beats() {
+        // A full shuffle puts every other eighth a third of a step late
+        let played = take(&[0, 250, 500, 750]);
+        let swung = quantize(Grid::Eighth, 1.0, 1.0).apply(&played, BPM);
+        assert_eq!(starts(&swung), [0, 333, 500, 833]);
+        let light = quantize(Grid::Eighth, 1.0, 0.5).apply(&played, BPM);
+        assert_eq!(starts(&light), [0, 292, 500, 792]);
+    }
+
+    #[test]
+    fn apply_leaves_the_raw_take_alone() {
+        let played = take(&[10, 130, 240]);
+        let quantized = quantize(Grid::Eighth, 1.0, 0.0).apply(&played, BPM);
+        assert_eq!(starts(&quantized), [0, 250, 250]);
+        assert_eq!(starts(&played), [10, 130, 240]);
+        assert_eq!(played.bpm, Some(BPM));
+    }
+}
@@ -1,15 +1,186 @@
+use crate::drums::DrumSound;
+use crate::instrument::InstrumentType;
+use crate::keyboard;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
 use std::time::{Duration, Instant};
 
-#[derive(Clone)]
-pub struct Note {
-    pub key: char,
-    pub timestamp: Duration,
+// How long a recorded key tap sounds for on playback
+pub const TAP_LENGTH: Duration = Duration::from_millis(150);
+
+/// One event of a performance. Untagged, so takes saved when only
+/// keyboard notes were recorded still load.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Note {
+    Key {
+        key: char,
+        timestamp: Duration,
+        #[serde(default = "tap_length")]
+        duration: Duration,
+        /// Set on overdubbed notes, which keep the instrument they were
+        /// played with whatever the take changes to around them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instrument: Option<InstrumentType>,
+        /// Semitones the keyboard was shifted by when the note was played
+        #[serde(default, skip_serializing_if = "is_unshifted")]
+        shift: i8,
+    },
+    Drum {
+        drum: DrumSound,
+        timestamp: Duration,
+    },
+    /// Keyboard notes after this play with `instrument`
+    Instrument {
+        instrument: InstrumentType,
+        timestamp: Duration,
+    },
+}
+
+fn tap_length() -> Duration {
+    TAP_LENGTH
+}
+
+fn is_unshifted(shift: &i8) -> bool {
+    *shift == 0
+}
+
+impl Note {
+    pub fn timestamp(&self) -> Duration {
+        match self {
+            Note::Key { timestamp, .. }
+            | Note::Drum { timestamp, .. }
+            | Note::Instrument { timestamp, .. } => *timestamp,
+        }
+    }
+
+    pub fn set_timestamp(&mut self, time: Duration) {
+        match self {
+            Note::Key { timestamp, .. }
+            | Note::Drum { timestamp, .. }
+            | Note::Instrument { timestamp, .. } => *timestamp = time,
+        }
+    }
+
+    /// When the note stops sounding; drum hits and instrument changes
+    /// count as instant.
+    pub fn end(&self) -> Duration {
+        match self {
+            Note::Key {
+                timestamp,
+                duration,
+                ..
+            } => *timestamp + *duration,
+            _ => self.timestamp(),
+        }
+    }
+
+    /// MIDI note a keyboard note sounds.
+    pub fn pitch(&self) -> Option<u8> {
+        match *self {
+            Note::Key { key, shift, .. } => keyboard::key_note(key, shift as i32),
+            _ => None,
+        }
+    }
+
+    /// Sets how long a key note is held; other events have no length.
+    pub fn set_duration(&mut self, length: Duration) {
+        if let Note::Key { duration, .. } = self {
+            *duration = length;
+        }
+    }
+}
+
+/// A finished recording, as played back and saved to disk.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Take {
+    pub notes: Vec<Note>,
+    #[serde(default)]
+    pub length: Duration,
+    /// Tempo the take was recorded at, so playback can follow the transport
+    #[serde(default)]
+    pub bpm: Option<f32>,
+    /// Sample kit its drum hits play from, kept with rendered takes so
+    /// `devtunes render` can load it again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kit: Option<String>,
+}
+
+impl Take {
+    /// The recorded length, stretched if needed so the last note isn't cut.
+    pub fn length(&self) -> Duration {
+        let last_note = self.notes.iter().map(Note::end).max().unwrap_or_default();
+        self.length.max(last_note)
+    }
+
+    /// The take shifted later by `delay`, to play along with a count-in.
+    pub fn delayed(&self, delay: Duration) -> Take {
+        let mut take = self.clone();
+        for note in &mut take.notes {
+            note.set_timestamp(note.timestamp() + delay);
+        }
+        take.length = self.length() + delay;
+        take
+    }
+
+    /// Layers `notes` on top of the take. Overdubbed notes are stamped with
+    /// their instrument so the two parts' instrument changes don't mix.
+    fn overdub(&mut self, notes: Vec<Note>, length: Duration) {
+        let mut current = None;
+        for mut note in notes {
+            match &mut note {
+                Note::Instrument { instrument, .. } => {
+                    current = Some(*instrument);
+                    continue;
+                }
+                Note::Key { instrument, .. } => *instrument = instrument.or(current),
+                Note::Drum { .. } => {}
+            }
+            self.notes.push(note);
+        }
+        self.notes.sort_by_key(Note::timestamp);
+        self.length = self.length.max(length);
+    }
+
+    /// Playback speed that brings the take in line with `bpm`.
+    pub fn tempo_ratio(&self, bpm: f32) -> f32 {
+        self.bpm.map_or(1.0, |take_bpm| bpm / take_bpm)
+    }
+}
+
+// Recording operations that can be undone
+const MAX_UNDO: usize = 32;
+
+// Takes and selection before a recording operation, for undo and redo
+struct History {
+    takes: Vec<Take>,
+    current: Option<usize>,
 }
 
 pub struct Recorder {
+    /// Events captured by the recording in progress
     pub recording: Vec<Note>,
     pub is_recording: bool,
+    /// Record on top of the current take instead of starting a new one
+    pub overdub: bool,
+    /// Finished takes, oldest first
+    pub takes: Vec<Take>,
+    pub current: Option<usize>,
+    bpm: Option<f32>,
     start_time: Option<Instant>,
+    // Take being overdubbed by the recording in progress
+    overdubbing: Option<usize>,
+    // Take time per second of wall-clock time, so overdubs land in time
+    // with a take recorded at another tempo
+    time_scale: f32,
+    // Index of the note each sounding MIDI note started, until its release
+    // arrives
+    held: HashMap<u8, usize>,
+    undo: Vec<History>,
+    redo: Vec<History>,
 }
 
 impl Recorder {
@@ -17,29 +188,325 @@ impl Recorder {
         Self {
             recording: Vec::new(),
             is_recording: false,
+            overdub: false,
+            takes: Vec::new(),
+            current: None,
+            bpm: None,
             start_time: None,
+            overdubbing: None,
+            time_scale: 1.0,
+            held: HashMap::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
         }
     }
 
-    pub fn start_recording(&mut self) {
+    /// Arms recording; capture begins once `count_in` has passed. The take
+    /// starts with `instrument` so it plays back the way it was recorded.
+    /// In overdub mode the new events go on top of the current take.
+    pub fn start_recording(&mut self, bpm: f32, count_in: Duration, instrument: InstrumentType) {
+        self.overdubbing = self.current.filter(|_| self.overdub);
+        let base = self.overdubbing.and_then(|index| self.takes.get(index));
+        self.bpm = base.map_or(Some(bpm), |take| take.bpm);
+        self.time_scale = base.map_or(1.0, |take| take.tempo_ratio(bpm));
+
         self.recording.clear();
+        self.held.clear();
         self.is_recording = true;
-        self.start_time = Some(Instant::now());
+        self.start_time = Some(Instant::now() + count_in);
+        self.record_instrument(instrument);
+    }
+
+    pub fn is_counting_in(&self) -> bool {
+        self.start_time.is_some_and(|start| Instant::now() < start)
+    }
+
+    pub fn is_overdubbing(&self) -> bool {
+        self.is_recording && self.overdubbing.is_some()
     }
 
+    /// Finishes the recording as a new take, or merges it into the take
+    /// being overdubbed. Recordings without a note or hit are dropped.
     pub fn stop_recording(&mut self) {
+        let Some(length) = self.elapsed() else {
+            return;
+        };
+        // Notes still held when recording stops end with the take
+        for (_, index) in self.held.drain() {
+            if let Some(note) = self.recording.get_mut(index) {
+                note.set_duration(length.saturating_sub(note.timestamp()));
+            }
+        }
         self.is_recording = false;
         self.start_time = None;
+
+        let notes = std::mem::take(&mut self.recording);
+        if notes
+            .iter()
+            .all(|note| matches!(note, Note::Instrument { .. }))
+        {
+            return;
+        }
+        self.checkpoint();
+        match self.overdubbing.and_then(|index| self.takes.get_mut(index)) {
+            Some(take) => take.overdub(notes, length),
+            None => {
+                self.takes.push(Take {
+                    notes,
+                    length,
+                    bpm: self.bpm,
+                    kit: None,
+                });
+                self.current = Some(self.takes.len() - 1);
+            }
+        }
+    }
+
+    /// Records a key press, `shift` semitones off the key's own note.
+    pub fn record_note(&mut self, key: char, shift: i8) {
+        if let Some(timestamp) = self.elapsed().filter(|_| !self.is_counting_in()) {
+            let note = Note::Key {
+                key,
+                timestamp,
+                duration: TAP_LENGTH,
+                instrument: None,
+                shift,
+            };
+            if let Some(pitch) = note.pitch() {
+                self.held.insert(pitch, self.recording.len());
+            }
+            self.recording.push(note);
+        }
+    }
+
+    pub fn record_drum(&mut self, drum: DrumSound) {
+        if let Some(timestamp) = self.elapsed().filter(|_| !self.is_counting_in()) {
+            self.recording.push(Note::Drum { drum, timestamp });
+        }
     }
 
-    pub fn record_note(&mut self, key: char) {
-        if let Some(start) = self.start_time {
-            let timestamp = start.elapsed();
-            self.recording.push(Note { key, timestamp });
+    /// Records an instrument change. Changes during the count-in land at
+    /// the start of the take, after the instrument it was started with.
+    pub fn record_instrument(&mut self, instrument: InstrumentType) {
+        if let Some(timestamp) = self.elapsed() {
+            self.recording.push(Note::Instrument {
+                instrument,
+                timestamp,
+            });
         }
     }
 
-    pub fn get_recording(&self) -> &Vec<Note> {
-        &self.recording
+    /// Ends the held note sounding MIDI note `pitch`.
+    pub fn record_release(&mut self, pitch: u8) {
+        if let (Some(elapsed), Some(index)) = (self.elapsed(), self.held.remove(&pitch)) {
+            if let Some(note) = self.recording.get_mut(index) {
+                note.set_duration(elapsed.saturating_sub(note.timestamp()));
+            }
+        }
+    }
+
+    // Time into the take being recorded, zero during the count-in
+    fn elapsed(&self) -> Option<Duration> {
+        self.start_time.map(|start| {
+            Instant::now()
+                .saturating_duration_since(start)
+                .mul_f32(self.time_scale)
+        })
+    }
+
+    /// The current take, or an empty one before anything was recorded.
+    pub fn take(&self) -> Take {
+        self.current
+            .and_then(|index| self.takes.get(index))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Replaces the take history, as when opening a project. Nothing from
+    /// before can be undone afterwards.
+    pub fn restore(&mut self, takes: Vec<Take>, current: Option<usize>) {
+        self.is_recording = false;
+        self.start_time = None;
+        self.recording.clear();
+        self.held.clear();
+        self.current = current.filter(|&index| index < takes.len());
+        self.takes = takes;
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Adds a take made elsewhere, such as an imported MIDI file, and
+    /// makes it current.
+    pub fn add_take(&mut self, take: Take) {
+        self.checkpoint();
+        self.takes.push(take);
+        self.current = Some(self.takes.len() - 1);
+    }
+
+    /// Steps through the take history, wrapping around at either end.
+    pub fn select_take(&mut self, steps: i32) {
+        if self.is_recording || self.takes.is_empty() {
+            return;
+        }
+        let count = self.takes.len() as i32;
+        let index = self.current.map_or(0, |index| index as i32 + steps);
+        self.current = Some(index.rem_euclid(count) as usize);
+    }
+
+    /// Position of the current take in the history, e.g. "2/3".
+    pub fn take_label(&self) -> String {
+        match self.current {
+            Some(index) => format!("{}/{}", index + 1, self.takes.len()),
+            None => "-".to_string(),
+        }
+    }
+
+    // Saves the takes before a recording operation changes them
+    fn checkpoint(&mut self) {
+        self.undo.push(History {
+            takes: self.takes.clone(),
+            current: self.current,
+        });
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    /// Reverts the last recording or overdub. Returns false if there was
+    /// nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        Self::swap_history(
+            &mut self.takes,
+            &mut self.current,
+            &mut self.undo,
+            &mut self.redo,
+            self.is_recording,
+        )
+    }
+
+    pub fn redo(&mut self) -> bool {
+        Self::swap_history(
+            &mut self.takes,
+            &mut self.current,
+            &mut self.redo,
+            &mut self.undo,
+            self.is_recording,
+        )
+    }
+
+    // Swaps in the latest state from `from`, keeping the replaced one on `to`
+    fn swap_history(
+        takes: &mut Vec<Take>,
+        current: &mut Option<usize>,
+        from: &mut Vec<History>,
+        to: &mut Vec<History>,
+        is_recording: bool,
+    ) -> bool {
+        if is_recording {
+            return false;
+        }
+        let Some(state) = from.pop() else {
+            return false;
+        };
+        to.push(History {
+            takes: std::mem::replace(takes, state.takes),
+            current: std::mem::replace(current, state.current),
+        });
+        true
+    }
+
+    pub fn save_take(take: &Take, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(take)?)?;
+        Ok(())
+    }
+
+    pub fn load_take(path: &Path) -> Result<Take, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records one take, or an overdub if the recorder is set to
+    fn record(recorder: &mut Recorder, drums: &[DrumSound]) {
+        recorder.start_recording(120.0, Duration::ZERO, InstrumentType::Sine);
+        for &drum in drums {
+            recorder.record_drum(drum);
+        }
+        recorder.stop_recording();
+    }
+
+    // Drum hits in the current take
+    fn hits(recorder: &Recorder) -> Vec<DrumSound> {
+        recorder
+            .take()
+            .notes
+            .iter()
+            .filter_map(|note| match *note {
+                Note::Drum { drum, .. } => Some(drum),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn undo_takes_back_an_overdub() {
+        let mut recorder = Recorder::new();
+        record(&mut recorder, &[DrumSound::Kick]);
+        recorder.overdub = true;
+        record(&mut recorder, &[DrumSound::Snare]);
+        assert_eq!(recorder.takes.len(), 1);
+        assert_eq!(hits(&recorder).len(), 2);
+
+        assert!(recorder.undo());
+        assert_eq!(recorder.takes.len(), 1);
+        assert_eq!(hits(&recorder), [DrumSound::Kick]);
+        assert!(recorder.redo());
+        assert_eq!(hits(&recorder).len(), 2);
+
+        // Back past the first take to nothing at all
+        assert!(recorder.undo());
+        assert!(recorder.undo());
+        assert!(recorder.takes.is_empty());
+        assert_eq!(recorder.current, None);
+        assert!(!recorder.undo());
+    }
+
+    #[test]
+    fn new_recording_clears_redo() {
+        let mut recorder = Recorder::new();
+        record(&mut recorder, &[DrumSound::Kick]);
+        record(&mut recorder, &[DrumSound::Snare]);
+        assert!(recorder.undo());
+        assert_eq!(recorder.take_label(), "1/1");
+
+        record(&mut recorder, &[DrumSound::HiHat]);
+        assert!(!recorder.redo());
+        assert_eq!(recorder.take_label(), "2/2");
+        assert_eq!(hits(&recorder), [DrumSound::HiHat]);
+
+        // Recording nothing isn't an edit, so it leaves redo alone
+        assert!(recorder.undo());
+        record(&mut recorder, &[]);
+        assert!(recorder.redo());
+        assert_eq!(hits(&recorder), [DrumSound::HiHat]);
+    }
+
+    #[test]
+    fn undo_history_is_capped() {
+        let mut recorder = Recorder::new();
+        for _ in 0..MAX_UNDO + 5 {
+            recorder.add_take(Take::default());
+        }
+        let mut undone = 0;
+        while recorder.undo() {
+            undone += 1;
+        }
+        assert_eq!(undone, MAX_UNDO);
+        // The oldest edits fell off the end and stay done
+        assert_eq!(recorder.takes.len(), 5);
     }
 }
@@ -0,0 +1,321 @@
+use crate::audio::{self, AudioCommand, PlaybackStatus, SequenceEvent};
+use crate::kit::KitSounds;
+use crate::mixer::{self, Mixer, CONTROL_BLOCK, SAMPLE_RATE};
+use crate::recorder::{Recorder, Take};
+use crate::sequencer::{PatternHits, STEP_TICKS};
+use crate::settings::Settings;
+use crate::transport::{self, TimeSignature, DEFAULT_BPM};
+use rtrb::RingBuffer;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Stop waiting for release tails after this long past the last event
+const MAX_TAIL_SECONDS: u32 = 10;
+
+/// The loop station and drum machine playing along with a take, at the
+/// transport's tempo.
+pub struct Backing {
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    /// Loop layers and their length, while the loop station is on
+    pub loops: Option<(Vec<SequenceEvent>, Duration)>,
+    pub drums: Vec<PatternHits>,
+    pub drums_looping: bool,
+}
+
+impl Backing {
+    /// Nothing but the take, at the tempo it was recorded at.
+    pub fn none(take: &Take) -> Self {
+        Self {
+            bpm: take.bpm.unwrap_or(DEFAULT_BPM),
+            time_signature: TimeSignature::default(),
+            loops: None,
+            drums: Vec::new(),
+            drums_looping: false,
+        }
+    }
+
+    // One pass through the drum arrangement
+    fn drums_length(&self) -> Duration {
+        let steps = self.drums.iter().map(|pattern| pattern.steps.len() as u64);
+        transport::ticks_to_duration(steps.sum::<u64>() * STEP_TICKS, self.bpm)
+    }
+}
+
+/// Renders a recording offline through the same mixer and voices the
+/// speakers use, without opening an audio device. The take plays at the
+/// backing's tempo, and everything runs for as long as the longest of
+/// the take, one pass of the loops and one of the drums.
+pub fn render_recording(
+    take: &Take,
+    backing: Backing,
+    settings: &Settings,
+    kit: KitSounds,
+) -> Vec<f32> {
+    let speed = take.tempo_ratio(backing.bpm);
+    let loops_length = backing.loops.as_ref().map(|&(_, length)| length);
+    let length = take
+        .length()
+        .div_f32(speed)
+        .max(loops_length.unwrap_or_default())
+        .max(backing.drums_length());
+
+    let mut commands = audio::settings_commands(settings);
+    commands.push(AudioCommand::SetKit(kit));
+    // The click is a guide for playing along, not part of the take
+    commands.push(AudioCommand::SetMetronome {
+        enabled: false,
+        volume: 0.0,
+    });
+    commands.push(AudioCommand::SetTempo(backing.bpm));
+    commands.push(AudioCommand::SetTimeSignature(backing.time_signature));
+    commands.push(AudioCommand::SetDrums {
+        patterns: backing.drums,
+        looping: backing.drums_looping,
+        at_bar: false,
+    });
+    if let Some((events, length)) = backing.loops {
+        commands.push(AudioCommand::PlayLoops { events, length });
+    }
+    commands.push(audio::recording_sequence(take, speed, false));
+    // Also starts the transport for a take with nothing in it
+    commands.push(AudioCommand::TransportPlay);
+
+    // Room for the stop commands sent once the length is up
+    let commands_len = commands.len() + 2;
+    let (mut producer, consumer) = RingBuffer::new(commands_len);
+    for command in commands {
+        // Sized to fit every command, so this can't fail
+        let _ = producer.push(command);
+    }
+
+    // Nothing here runs in real time, so retired buffers can just pile up
+    let (retire, _retired) = RingBuffer::new(commands_len);
+    let mut mixer = Mixer::new(consumer, retire, Arc::new(PlaybackStatus::default()));
+    // Commands only land on a control block boundary, so the stop goes in
+    // on the last one before the end rather than after the loops and drums
+    // have started over
+    let end = (mixer::to_samples(length) / CONTROL_BLOCK * CONTROL_BLOCK) as usize;
+    let mut samples = Vec::with_capacity(end);
+    // The first sample also pulls the queued commands into the mixer
+    while samples.len() < end {
+        samples.extend(mixer.next());
+    }
+
+    // Stop everything that would otherwise repeat, then let the voices
+    // still sounding ring out
+    let _ = producer.push(AudioCommand::TransportStop);
+    let _ = producer.push(AudioCommand::StopLoops);
+    let max_tail = SAMPLE_RATE * MAX_TAIL_SECONDS;
+    let mut tail = 0;
+    loop {
+        samples.extend(mixer.next());
+        tail += 1;
+        if mixer.is_idle() || tail > max_tail {
+            break;
+        }
+    }
+    samples
+}
+
+/// Writes mono samples as a 16- or 24-bit PCM WAV file.
+pub fn write_wav(path: &Path, samples: &[f32], bits: u16) -> Result<(), Box<dyn Error>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: bits,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+
+    match bits {
+        16 => {
+            for &sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+            }
+        }
+        24 => {
+            const MAX_24: f32 = 8_388_607.0;
+            for &sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * MAX_24) as i32)?;
+            }
+        }
+        _ => return Err(format!("Unsupported bit depth: {}", bits).into()),
+    }
+
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Renders a take with its backing to `devtunes-<timestamp>.wav` in the
+/// working directory, saving the take next to it so it can be re-rendered
+/// later.
+pub fn export_take(
+    take: &Take,
+    backing: Backing,
+    settings: &Settings,
+    kit: KitSounds,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = PathBuf::from(format!("devtunes-{}.wav", timestamp));
+
+    let samples = render_recording(take, backing, settings, kit);
+    write_wav(&path, &samples, 16)?;
+    Recorder::save_take(take, &path.with_extension("json"))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SequenceSound;
+    use crate::drums::DrumSound;
+    use crate::instrument::InstrumentType;
+    use crate::recorder::Note;
+
+    // Set to write the renders out as the new golden files
+    const UPDATE_VAR: &str = "DEVTUNES_UPDATE_GOLDEN";
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn key(key: char, at: u64, shift: i8) -> Note {
+        Note::Key {
+            key,
+            timestamp: ms(at),
+            duration: ms(200),
+            instrument: None,
+            shift,
+        }
+    }
+
+    fn take() -> Take {
+        Take {
+            notes: vec![
+                Note::Instrument {
+                    instrument: InstrumentType::Square,
+                    timestamp: ms(0),
+                },
+                key('a', 0, 0),
+                key('d', 250, 12),
+                Note::Drum {
+                    drum: DrumSound::Snare,
+                    timestamp: ms(500),
+                },
+                Note::Instrument {
+                    instrument: InstrumentType::Triangle,
+                    timestamp: ms(600),
+                },
+                key('g', 750, -12),
+            ],
+            length: ms(1000),
+            bpm: Some(120.0),
+            kit: None,
+        }
+    }
+
+    // A bar of 3/4 at 180 BPM, a second long
+    fn backing() -> Backing {
+        let loop_note = |at, note| SequenceEvent {
+            at: ms(at),
+            sound: SequenceSound::Note {
+                note,
+                duration: ms(250),
+                instrument: Some(InstrumentType::Saw),
+            },
+        };
+        let mut steps = vec![Vec::new(); 12];
+        steps[0].push((DrumSound::Kick, 1.0));
+        steps[6].push((DrumSound::Kick, 0.7));
+        for step in (2..12).step_by(4) {
+            steps[step].push((DrumSound::HiHat, 0.5));
+        }
+        Backing {
+            bpm: 180.0,
+            time_signature: TimeSignature { beats: 3, unit: 4 },
+            loops: Some((vec![loop_note(0, 45), loop_note(500, 52)], ms(1000))),
+            drums: vec![PatternHits { slot: 0, steps }],
+            drums_looping: true,
+        }
+    }
+
+    fn to_pcm(samples: &[f32]) -> Vec<i16> {
+        samples
+            .iter()
+            .map(|&sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect()
+    }
+
+    /// Compares a render against `tests/golden/<name>.wav`, allowing for a
+    /// step of rounding either way.
+    fn check_golden(name: &str, samples: &[f32]) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(name)
+            .with_extension("wav");
+        if std::env::var_os(UPDATE_VAR).is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            write_wav(&path, samples, 16).unwrap();
+        }
+        let mut reader = hound::WavReader::open(&path).unwrap_or_else(|e| {
+            panic!("{}: {} (set {} to write it)", path.display(), e, UPDATE_VAR)
+        });
+        let golden = reader
+            .samples::<i16>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let rendered = to_pcm(samples);
+        assert_eq!(rendered.len(), golden.len(), "{} length", name);
+        for (i, (&a, &b)) in rendered.iter().zip(&golden).enumerate() {
+            assert!(
+                (a as i32 - b as i32).abs() <= 1,
+                "{} differs at sample {}: {} vs {}",
+                name,
+                i,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn take_matches_golden() {
+        let take = take();
+        let samples = render_recording(
+            &take,
+            Backing::none(&take),
+            &Settings::default(),
+            Vec::new(),
+        );
+        assert!(samples.len() >= SAMPLE_RATE as usize);
+        check_golden("take", &samples);
+    }
+
+    #[test]
+    fn backing_matches_golden() {
+        let samples = render_recording(&take(), backing(), &Settings::default(), Vec::new());
+        check_golden("backing", &samples);
+    }
+
+    #[test]
+    fn backing_sets_the_length() {
+        let take = Take {
+            notes: vec![key('a', 0, 0)],
+            length: ms(200),
+            ..take()
+        };
+        let alone = render_recording(
+            &take,
+            Backing::none(&take),
+            &Settings::default(),
+            Vec::new(),
+        );
+        let backed = render_recording(&take, backing(), &Settings::default(), Vec::new());
+        assert!(alone.len() < SAMPLE_RATE as usize);
+        assert!(backed.len() >= SAMPLE_RATE as usize);
+    }
+}
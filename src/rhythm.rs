@@ -0,0 +1,201 @@
+use crate::sequencer::{Pattern, Velocity};
+
+/// Small xorshift generator so the same seed always gives the same groove.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Spread nearby seeds apart; xorshift must never start at zero
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f32) -> bool {
+        ((self.next_u64() >> 40) as f32) < p * (1u64 << 24) as f32
+    }
+}
+
+/// `hits` onsets spread as evenly as possible over `steps`, rotated
+/// `rotation` steps to the right.
+pub fn euclidean(hits: usize, steps: usize, rotation: usize) -> Vec<bool> {
+    if steps == 0 {
+        return Vec::new();
+    }
+    (0..steps)
+        .map(|i| {
+            let i = (i + steps - rotation % steps) % steps;
+            (i * hits) % steps < hits
+        })
+        .collect()
+}
+
+/// Replaces a row with a Euclidean rhythm over the pattern's length.
+pub fn fill_euclidean(pattern: &mut Pattern, key: char, hits: usize, rotation: usize) {
+    for (step, hit) in euclidean(hits, pattern.length, rotation)
+        .into_iter()
+        .enumerate()
+    {
+        let velocity = if hit { Velocity::Normal } else { Velocity::Off };
+        pattern.set_step(key, step, velocity);
+    }
+}
+
+/// Rotates a row one step to the right within the pattern's length.
+pub fn rotate(pattern: &mut Pattern, key: char) {
+    if pattern.length == 0 {
+        return;
+    }
+    let row = (0..pattern.length)
+        .map(|step| pattern.step(key, step))
+        .collect::<Vec<_>>();
+    for (step, velocity) in row.into_iter().enumerate() {
+        pattern.set_step(key, (step + 1) % pattern.length, velocity);
+    }
+}
+
+/// Nudges the velocity of existing hits on the given rows up or down a
+/// level, so repeated hats and snares don't all sound the same.
+pub fn humanize(pattern: &mut Pattern, keys: &[char], seed: u64) {
+    let mut rng = Rng::new(seed);
+    for &key in keys {
+        for step in 0..pattern.length {
+            let velocity = pattern.step(key, step);
+            if velocity == Velocity::Off || !rng.chance(0.5) {
+                continue;
+            }
+            let velocity = match (velocity, rng.chance(0.5)) {
+                (Velocity::Soft, _) | (Velocity::Accent, true) => Velocity::Normal,
+                (Velocity::Normal, true) => Velocity::Soft,
+                (Velocity::Normal, false) => Velocity::Accent,
+                (other, _) => other,
+            };
+            pattern.set_step(key, step, velocity);
+        }
+    }
+}
+
+/// Scatters soft hats on empty offbeat sixteenths and snare ghost notes
+/// on empty steps away from the beat.
+pub fn random_fill(pattern: &mut Pattern, snare: char, hihat: char, seed: u64) {
+    let mut rng = Rng::new(seed);
+    for step in 0..pattern.length {
+        if step % 2 == 1 && pattern.step(hihat, step) == Velocity::Off && rng.chance(0.35) {
+            pattern.set_step(hihat, step, Velocity::Soft);
+        }
+        if step % 4 != 0 && pattern.step(snare, step) == Velocity::Off && rng.chance(0.15) {
+            pattern.set_step(snare, step, Velocity::Soft);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNARE: char = 'x';
+    const HIHAT: char = 'c';
+
+    fn onsets(rhythm: &[bool]) -> Vec<usize> {
+        (0..rhythm.len()).filter(|&step| rhythm[step]).collect()
+    }
+
+    fn groove() -> Pattern {
+        let mut pattern = Pattern::new();
+        for step in (0..16).step_by(2) {
+            pattern.set_step(HIHAT, step, Velocity::Normal);
+        }
+        pattern.set_step(HIHAT, 7, Velocity::Accent);
+        pattern.set_step(SNARE, 4, Velocity::Normal);
+        pattern.set_step(SNARE, 12, Velocity::Accent);
+        pattern.set_step(SNARE, 14, Velocity::Soft);
+        pattern
+    }
+
+    #[test]
+    fn euclidean_spreads_hits_evenly() {
+        assert_eq!(onsets(&euclidean(3, 8, 0)), [0, 3, 6]);
+        assert_eq!(onsets(&euclidean(5, 16, 0)), [0, 4, 7, 10, 13]);
+    }
+
+    #[test]
+    fn euclidean_rotates_right() {
+        assert_eq!(onsets(&euclidean(3, 8, 2)), [0, 2, 5]);
+        assert_eq!(onsets(&euclidean(5, 16, 3)), [0, 3, 7, 10, 13]);
+        // Whole turns come back round
+        assert_eq!(euclidean(5, 16, 19), euclidean(5, 16, 3));
+    }
+
+    #[test]
+    fn euclidean_edge_cases() {
+        assert_eq!(euclidean(0, 8, 0), [false; 8]);
+        assert_eq!(euclidean(8, 8, 5), [true; 8]);
+        assert!(euclidean(3, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn humanize_is_deterministic_per_seed() {
+        let humanized = |seed| {
+            let mut pattern = groove();
+            humanize(&mut pattern, &[SNARE, HIHAT], seed);
+            pattern.rows
+        };
+        assert_eq!(humanized(7), humanized(7));
+        assert!(humanized(7) != humanized(8));
+    }
+
+    #[test]
+    fn humanize_only_changes_hits() {
+        let original = groove();
+        for seed in 0..32 {
+            let mut pattern = groove();
+            humanize(&mut pattern, &[SNARE, HIHAT], seed);
+            for key in [SNARE, HIHAT] {
+                for step in 0..pattern.length {
+                    let before = original.step(key, step);
+                    assert_eq!(
+                        before == Velocity::Off,
+                        pattern.step(key, step) == Velocity::Off
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_fill_is_deterministic_per_seed() {
+        let filled = |seed| {
+            let mut pattern = groove();
+            random_fill(&mut pattern, SNARE, HIHAT, seed);
+            pattern.rows
+        };
+        assert_eq!(filled(3), filled(3));
+        assert!(filled(3) != filled(4));
+    }
+
+    #[test]
+    fn random_fill_only_touches_empty_offbeats() {
+        let original = groove();
+        for seed in 0..32 {
+            let mut pattern = groove();
+            random_fill(&mut pattern, SNARE, HIHAT, seed);
+            for step in 0..pattern.length {
+                for (key, offbeat) in [(HIHAT, step % 2 == 1), (SNARE, step % 4 != 0)] {
+                    let before = original.step(key, step);
+                    let after = pattern.step(key, step);
+                    if before != Velocity::Off || !offbeat {
+                        assert_eq!(after, before, "{} step {} seed {}", key, step, seed);
+                    } else {
+                        assert!(matches!(after, Velocity::Off | Velocity::Soft));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::drums::DrumSound;
+use crate::rhythm;
+use crate::transport::PPQN;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const DEFAULT_STEPS: usize = 16;
+pub const MAX_STEPS: usize = 64;
+pub const BANK_SLOTS: usize = 8;
+
+/// Each step is a sixteenth note
+pub const STEP_TICKS: u64 = PPQN / 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Velocity {
+    #[default]
+    Off,
+    Soft,
+    Normal,
+    Accent,
+}
+
+impl Velocity {
+    pub fn gain(&self) -> f32 {
+        match self {
+            Velocity::Off => 0.0,
+            Velocity::Soft => 0.4,
+            Velocity::Normal => 0.75,
+            Velocity::Accent => 1.0,
+        }
+    }
+
+    pub fn symbol(&self) -> char {
+        match self {
+            Velocity::Off => '·',
+            Velocity::Soft => '○',
+            Velocity::Normal => '●',
+            Velocity::Accent => '◉',
+        }
+    }
+
+    /// Cycles the velocity of a step that is on.
+    pub fn next(&self) -> Velocity {
+        match self {
+            Velocity::Off => Velocity::Off,
+            Velocity::Soft => Velocity::Normal,
+            Velocity::Normal => Velocity::Accent,
+            Velocity::Accent => Velocity::Soft,
+        }
+    }
+}
+
+/// A drum pattern: one row of steps per pad key. Rows hold `MAX_STEPS`
+/// steps so shortening a pattern doesn't lose anything.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pattern {
+    pub length: usize,
+    pub rows: HashMap<char, Vec<Velocity>>,
+}
+
+impl Pattern {
+    pub fn new() -> Self {
+        Self {
+            length: DEFAULT_STEPS,
+            rows: HashMap::new(),
+        }
+    }
+
+    pub fn step(&self, key: char, step: usize) -> Velocity {
+        self.rows
+            .get(&key)
+            .and_then(|row| row.get(step))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_step(&mut self, key: char, step: usize, velocity: Velocity) {
+        if step < MAX_STEPS {
+            let row = self.rows.entry(key).or_default();
+            row.resize(MAX_STEPS, Velocity::Off);
+            row[step] = velocity;
+        }
+    }
+
+    /// Hits for each step, resolved against the current pad sounds.
+    pub fn hits(&self, sounds: &HashMap<char, DrumSound>) -> Vec<Vec<(DrumSound, f32)>> {
+        (0..self.length)
+            .map(|step| {
+                sounds
+                    .iter()
+                    .filter_map(|(&key, &sound)| {
+                        let velocity = self.step(key, step);
+                        (velocity != Velocity::Off).then(|| (sound, velocity.gain()))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// One pattern's worth of hits, as handed to the mixer.
+pub struct PatternHits {
+    pub slot: usize,
+    pub steps: Vec<Vec<(DrumSound, f32)>>,
+}
+
+pub fn slot_name(slot: usize) -> char {
+    (b'A' + slot as u8) as char
+}
+
+/// Pattern slots A-H and the song arranged from them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PatternBank {
+    pub patterns: Vec<Pattern>,
+    /// Slots to play in order when song mode is on
+    pub song: Vec<usize>,
+}
+
+impl PatternBank {
+    pub fn new() -> Self {
+        Self {
+            patterns: vec![Pattern::new(); BANK_SLOTS],
+            song: Vec::new(),
+        }
+    }
+}
+
+/// Grid editor state for the drum machine.
+pub struct StepSequencer {
+    pub bank: PatternBank,
+    /// Slot being edited, and played outside song mode
+    pub current: usize,
+    pub song_mode: bool,
+    pub visible: bool,
+    pub cursor_row: usize,
+    pub cursor_step: usize,
+    /// Seed of the last humanize or random fill
+    pub seed: u64,
+}
+
+impl StepSequencer {
+    pub fn new() -> Self {
+        Self {
+            bank: PatternBank::new(),
+            current: 0,
+            song_mode: false,
+            visible: false,
+            cursor_row: 0,
+            cursor_step: 0,
+            seed: 0,
+        }
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.bank.patterns[self.current]
+    }
+
+    fn pattern_mut(&mut self) -> &mut Pattern {
+        &mut self.bank.patterns[self.current]
+    }
+
+    pub fn select(&mut self, slot: usize) {
+        if slot < self.bank.patterns.len() {
+            self.current = slot;
+            self.cursor_step = self
+                .cursor_step
+                .min(self.pattern().length.saturating_sub(1));
+        }
+    }
+
+    /// Appends the current slot to the end of the song.
+    pub fn append_to_song(&mut self) {
+        self.bank.song.push(self.current);
+    }
+
+    pub fn remove_from_song(&mut self) {
+        self.bank.song.pop();
+    }
+
+    /// What the drum machine should play: the song once through in song
+    /// mode, otherwise the current pattern on repeat. Returns the patterns
+    /// and whether they loop.
+    pub fn arrangement(&self, sounds: &HashMap<char, DrumSound>) -> (Vec<PatternHits>, bool) {
+        let hits = |slot: usize| PatternHits {
+            slot,
+            steps: self.bank.patterns[slot].hits(sounds),
+        };
+        if self.song_mode && !self.bank.song.is_empty() {
+            (
+                self.bank.song.iter().map(|&slot| hits(slot)).collect(),
+                false,
+            )
+        } else {
+            (vec![hits(self.current)], true)
+        }
+    }
+
+    pub fn move_cursor(&mut self, rows: i32, steps: i32, row_count: usize) {
+        let row_count = row_count.max(1) as i32;
+        let length = self.pattern().length as i32;
+        self.cursor_row = (self.cursor_row as i32 + rows).rem_euclid(row_count) as usize;
+        self.cursor_step = (self.cursor_step as i32 + steps).rem_euclid(length) as usize;
+    }
+
+    /// Turns the step under the cursor on or off.
+    pub fn toggle(&mut self, key: char) {
+        let velocity = match self.pattern().step(key, self.cursor_step) {
+            Velocity::Off => Velocity::Normal,
+            _ => Velocity::Off,
+        };
+        let step = self.cursor_step;
+        self.pattern_mut().set_step(key, step, velocity);
+    }
+
+    pub fn cycle_velocity(&mut self, key: char) {
+        let velocity = self.pattern().step(key, self.cursor_step).next();
+        let step = self.cursor_step;
+        self.pattern_mut().set_step(key, step, velocity);
+    }
+
+    /// Adds a hit to a row and respaces it as a Euclidean rhythm, wrapping
+    /// back to an empty row once every step is filled.
+    pub fn euclid_row(&mut self, key: char) {
+        let pattern = self.pattern_mut();
+        let hits = (0..pattern.length)
+            .filter(|&step| pattern.step(key, step) != Velocity::Off)
+            .count();
+        rhythm::fill_euclidean(pattern, key, (hits + 1) % (pattern.length + 1), 0);
+    }
+
+    pub fn rotate_row(&mut self, key: char) {
+        rhythm::rotate(self.pattern_mut(), key);
+    }
+
+    pub fn humanize(&mut self, keys: &[char]) {
+        let seed = self.next_seed();
+        rhythm::humanize(self.pattern_mut(), keys, seed);
+    }
+
+    pub fn random_fill(&mut self, snare: char, hihat: char) {
+        let seed = self.next_seed();
+        rhythm::random_fill(self.pattern_mut(), snare, hihat, seed);
+    }
+
+    // Each generated fill gets a fresh seed, so pressing again varies it
+    fn next_seed(&mut self) -> u64 {
+        self.seed = self.seed.wrapping_add(1);
+        self.seed
+    }
+
+    pub fn resize(&mut self, steps: i32) {
+        let length = self.pattern().length as i32 + steps;
+        self.pattern_mut().length = length.clamp(1, MAX_STEPS as i32) as usize;
+        self.cursor_step = self
+            .cursor_step
+            .min(self.pattern().length.saturating_sub(1));
+    }
+}
+
+/// Where the drum machine is: song entry, pattern slot and step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrumPosition {
+    pub entry: usize,
+    pub slot: usize,
+    pub step: usize,
+}
+
+/// The mixer's side of the step sequencer: plays an arrangement of
+/// patterns one step at a time, looping or once through.
+pub struct DrumMachine {
+    patterns: Vec<PatternHits>,
+    looping: bool,
+    entry: usize,
+    step: usize,
+    // Arrangement waiting for the next bar line
+    queued: Option<(Vec<PatternHits>, bool)>,
+    pub position: Option<DrumPosition>,
+}
+
+impl DrumMachine {
+    pub fn new() -> Self {
+        Self {
+            patterns: Vec::new(),
+            looping: true,
+            entry: 0,
+            step: 0,
+            queued: None,
+            position: None,
+        }
+    }
+
+    /// Replaces the arrangement. Queued changes wait for `start_queued` at
+    /// a bar line; the others take over straight away, keeping their place
+    /// if `rewind` is false. Returns the arrangements it displaced, so the
+    /// mixer can have them freed off the audio thread.
+    pub fn set(
+        &mut self,
+        patterns: Vec<PatternHits>,
+        looping: bool,
+        queue: bool,
+        rewind: bool,
+    ) -> impl Iterator<Item = Vec<PatternHits>> {
+        if queue && self.position.is_some() {
+            let queued = self.queued.replace((patterns, looping));
+            return [queued.map(|(old, _)| old), None].into_iter().flatten();
+        }
+        let old = std::mem::replace(&mut self.patterns, patterns);
+        let queued = self.queued.take();
+        self.looping = looping;
+        if rewind || self.entry >= self.patterns.len() {
+            self.rewind();
+        } else if self.step >= self.patterns[self.entry].steps.len() {
+            self.step = 0;
+        }
+        [Some(old), queued.map(|(old, _)| old)]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Returns the arrangement the queued one took over from.
+    pub fn start_queued(&mut self) -> Option<Vec<PatternHits>> {
+        let (patterns, looping) = self.queued.take()?;
+        self.looping = looping;
+        self.rewind();
+        Some(std::mem::replace(&mut self.patterns, patterns))
+    }
+
+    pub fn rewind(&mut self) {
+        self.entry = 0;
+        self.step = 0;
+        self.position = None;
+    }
+
+    /// Hits of the step that plays now, moving on to the next one.
+    pub fn advance(&mut self) -> &[(DrumSound, f32)] {
+        let Some(pattern) = self
+            .patterns
+            .get(self.entry)
+            .filter(|pattern| !pattern.steps.is_empty())
+        else {
+            self.position = None;
+            return &[];
+        };
+        let (entry, step) = (self.entry, self.step);
+        self.position = Some(DrumPosition {
+            entry,
+            slot: pattern.slot,
+            step,
+        });
+
+        self.step += 1;
+        if self.step >= pattern.steps.len() {
+            self.step = 0;
+            self.entry += 1;
+            if self.entry >= self.patterns.len() && self.looping {
+                self.entry = 0;
+            }
+        }
+        &self.patterns[entry].steps[step]
+    }
+}
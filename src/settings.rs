@@ -0,0 +1,74 @@
+use crate::envelope::Envelope;
+use crate::instrument::InstrumentType;
+use crate::sync::SyncMode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// User preferences that survive restarts, stored as JSON in the config dir.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub instrument: InstrumentType,
+    pub envelopes: HashMap<InstrumentType, Envelope>,
+    pub lofi: bool,
+    pub metronome: bool,
+    pub metronome_volume: f32,
+    /// Bars of clicks before a recording starts capturing
+    pub count_in: u32,
+    /// Keyboard octave and transpose, in octaves and semitones
+    pub octave: i32,
+    pub transpose: i32,
+    /// MIDI port notes are sent to, reconnected on launch
+    pub midi_output: Option<String>,
+    /// Leave the internal synth silent while MIDI output is on
+    pub midi_only: bool,
+    /// Whether the transport sends or follows MIDI clock
+    pub sync: SyncMode,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            instrument: InstrumentType::default(),
+            envelopes: HashMap::new(),
+            lofi: false,
+            metronome: false,
+            metronome_volume: 0.5,
+            count_in: 1,
+            octave: 0,
+            transpose: 0,
+            midi_output: None,
+            midi_only: false,
+            sync: SyncMode::Internal,
+        }
+    }
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("devtunes").join("settings.json"))
+    }
+
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = Self::path().ok_or("No config directory available")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn envelope(&self, instrument: InstrumentType) -> Envelope {
+        self.envelopes.get(&instrument).copied().unwrap_or_default()
+    }
+}
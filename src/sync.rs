@@ -0,0 +1,132 @@
+use crate::transport::{MAX_BPM, MIN_BPM, PPQN};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Transport ticks per MIDI clock pulse, which runs at 24 PPQN
+pub const PULSE_TICKS: u64 = PPQN / 24;
+
+/// A gap between pulses longer than this means the clock has stopped
+pub const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+const QUEUE_SIZE: usize = 256;
+// Pulses the tempo is averaged over, a beat's worth
+const WINDOW: usize = 24;
+// Fewer pulses than this are too jittery to go by
+const MIN_PULSES: usize = 4;
+// Share of each new reading in the smoothed tempo
+const SMOOTHING: f64 = 0.1;
+
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const SONG_POSITION: u8 = 0xF2;
+
+/// Where the transport takes its timing from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMode {
+    #[default]
+    Internal,
+    /// Sends MIDI clock out on the output port
+    Master,
+    /// Follows MIDI clock coming in on any input
+    Slave,
+}
+
+impl SyncMode {
+    pub fn next(self) -> SyncMode {
+        match self {
+            SyncMode::Internal => SyncMode::Master,
+            SyncMode::Master => SyncMode::Slave,
+            SyncMode::Slave => SyncMode::Internal,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SyncMode::Internal => "Internal",
+            SyncMode::Master => "Send clock",
+            SyncMode::Slave => "Follow clock",
+        }
+    }
+}
+
+/// Clock messages from a MIDI input, passed straight to the mixer so the
+/// UI loop's polling doesn't add to their jitter.
+#[derive(Clone, Copy)]
+pub enum SyncMessage {
+    /// A pulse, with the smoothed tempo once there's enough to measure
+    Clock(Option<f32>),
+    Start,
+    Continue,
+    Stop,
+}
+
+/// Every input connection feeds the same queue
+pub type SyncSender = Arc<Mutex<Producer<SyncMessage>>>;
+
+pub fn queue() -> (SyncSender, Consumer<SyncMessage>) {
+    let (producer, consumer) = RingBuffer::new(QUEUE_SIZE);
+    (Arc::new(Mutex::new(producer)), consumer)
+}
+
+/// Measures the tempo of incoming clock. Averaging over a beat of pulses
+/// and then smoothing the result irons out jitter in their arrival.
+pub struct TempoMeter {
+    pulses: VecDeque<Instant>,
+    bpm: Option<f64>,
+}
+
+impl TempoMeter {
+    pub fn new() -> Self {
+        Self {
+            pulses: VecDeque::with_capacity(WINDOW + 1),
+            bpm: None,
+        }
+    }
+
+    /// Registers a pulse and returns the tempo measured so far.
+    pub fn pulse(&mut self, at: Instant) -> Option<f32> {
+        if self
+            .pulses
+            .back()
+            .is_some_and(|last| at.duration_since(*last) > CLOCK_TIMEOUT)
+        {
+            self.pulses.clear();
+            self.bpm = None;
+        }
+        self.pulses.push_back(at);
+        if self.pulses.len() > WINDOW + 1 {
+            self.pulses.pop_front();
+        }
+        if self.pulses.len() < MIN_PULSES {
+            return None;
+        }
+
+        let span = at.duration_since(self.pulses[0]).as_secs_f64();
+        if span <= 0.0 {
+            return None;
+        }
+        let measured = 60.0 * (self.pulses.len() - 1) as f64 / (24.0 * span);
+        let bpm = match self.bpm {
+            Some(bpm) => bpm + (measured - bpm) * SMOOTHING,
+            None => measured,
+        };
+        self.bpm = Some(bpm);
+        Some((bpm as f32).clamp(MIN_BPM, MAX_BPM))
+    }
+}
+
+/// Decodes the real-time messages that drive the transport.
+pub fn parse(message: &[u8], meter: &mut TempoMeter) -> Option<SyncMessage> {
+    match *message.first()? {
+        CLOCK => Some(SyncMessage::Clock(meter.pulse(Instant::now()))),
+        START => Some(SyncMessage::Start),
+        CONTINUE => Some(SyncMessage::Continue),
+        STOP => Some(SyncMessage::Stop),
+        _ => None,
+    }
+}
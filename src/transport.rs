@@ -0,0 +1,219 @@
+use crate::mixer::SAMPLE_RATE;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Ticks per quarter note
+pub const PPQN: u64 = 96;
+
+pub const MIN_BPM: f32 = 20.0;
+pub const MAX_BPM: f32 = 300.0;
+pub const DEFAULT_BPM: f32 = 120.0;
+
+// Taps further apart than this start a new tap tempo measurement
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_TAPS: usize = 5;
+
+// Share of the gap to an external clock closed on each of its pulses
+const PHASE_GAIN: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+impl TransportState {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TransportState::Playing,
+            2 => TransportState::Paused,
+            _ => TransportState::Stopped,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TransportState::Stopped => "■",
+            TransportState::Playing => "▶",
+            TransportState::Paused => "⏸",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub beats: u32,
+    pub unit: u32,
+}
+
+impl TimeSignature {
+    pub const ALL: [TimeSignature; 5] = [
+        TimeSignature { beats: 4, unit: 4 },
+        TimeSignature { beats: 3, unit: 4 },
+        TimeSignature { beats: 6, unit: 8 },
+        TimeSignature { beats: 5, unit: 4 },
+        TimeSignature { beats: 7, unit: 8 },
+    ];
+
+    pub fn next(&self) -> TimeSignature {
+        let index = Self::ALL.iter().position(|ts| ts == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn ticks_per_beat(&self) -> u64 {
+        PPQN * 4 / self.unit as u64
+    }
+
+    pub fn ticks_per_bar(&self) -> u64 {
+        self.ticks_per_beat() * self.beats as u64
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature { beats: 4, unit: 4 }
+    }
+}
+
+impl std::fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.beats, self.unit)
+    }
+}
+
+/// Tempo and meter, as edited from the UI. The running position lives in
+/// the mixer's `Clock` so it stays sample accurate.
+pub struct Transport {
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    taps: Vec<Instant>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self {
+            bpm: DEFAULT_BPM,
+            time_signature: TimeSignature::default(),
+            taps: Vec::new(),
+        }
+    }
+
+    pub fn adjust_bpm(&mut self, delta: f32) {
+        self.bpm = (self.bpm.round() + delta).clamp(MIN_BPM, MAX_BPM);
+    }
+
+    /// Registers a tap and, once there are at least two, sets the tempo
+    /// from the average interval between recent taps.
+    pub fn tap(&mut self) {
+        let now = Instant::now();
+        if self
+            .taps
+            .last()
+            .is_some_and(|last| now.duration_since(*last) > TAP_TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        self.taps.push(now);
+        if self.taps.len() > MAX_TAPS {
+            self.taps.remove(0);
+        }
+
+        let intervals = self.taps.len() as u32 - 1;
+        if intervals > 0 {
+            let interval = now.duration_since(self.taps[0]) / intervals;
+            self.bpm = (60.0 / interval.as_secs_f32()).clamp(MIN_BPM, MAX_BPM);
+        }
+    }
+
+    pub fn bar_duration(&self) -> Duration {
+        ticks_to_duration(self.time_signature.ticks_per_bar(), self.bpm)
+    }
+
+    /// Formats a tick count as a 1-based bar:beat:tick position.
+    pub fn position(&self, ticks: u64) -> String {
+        let ticks_per_beat = self.time_signature.ticks_per_beat();
+        let ticks_per_bar = self.time_signature.ticks_per_bar();
+        format!(
+            "{}:{}:{:02}",
+            ticks / ticks_per_bar + 1,
+            ticks % ticks_per_bar / ticks_per_beat + 1,
+            ticks % ticks_per_beat
+        )
+    }
+}
+
+/// Tick at which a count-in of `bars` that starts at `ticks` ends. The
+/// count-in runs from the next bar line so capture starts on a downbeat.
+pub fn count_in_end(ticks: u64, bars: u32, time_signature: TimeSignature) -> u64 {
+    if bars == 0 {
+        return ticks;
+    }
+    let ticks_per_bar = time_signature.ticks_per_bar();
+    ticks.next_multiple_of(ticks_per_bar) + bars as u64 * ticks_per_bar
+}
+
+pub fn ticks_to_duration(ticks: u64, bpm: f32) -> Duration {
+    Duration::from_secs_f64(ticks as f64 * 60.0 / (bpm as f64 * PPQN as f64))
+}
+
+pub fn duration_to_ticks(duration: Duration, bpm: f32) -> u64 {
+    (duration.as_secs_f64() * bpm as f64 * PPQN as f64 / 60.0).round() as u64
+}
+
+/// Sample-accurate tempo clock, advanced by the mixer once per sample.
+pub struct Clock {
+    pub state: TransportState,
+    pub bpm: f32,
+    ticks: f64,
+    next_tick: u64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            state: TransportState::Stopped,
+            bpm: DEFAULT_BPM,
+            ticks: 0.0,
+            next_tick: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.state == TransportState::Playing
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks as u64
+    }
+
+    pub fn stop(&mut self) {
+        self.state = TransportState::Stopped;
+        self.ticks = 0.0;
+        self.next_tick = 0;
+    }
+
+    /// Pulls the position part of the way towards `ticks`, where an
+    /// external clock says it should be. Ticks jumped over still fire.
+    /// Returns how far it moved, in samples at the current tempo.
+    pub fn follow(&mut self, ticks: u64) -> f64 {
+        let moved = (ticks as f64 - self.ticks) * PHASE_GAIN;
+        self.ticks += moved;
+        moved * 60.0 * SAMPLE_RATE as f64 / (self.bpm as f64 * PPQN as f64)
+    }
+
+    /// Moves on by one sample while playing. Returns the tick that starts
+    /// on this sample, if any.
+    pub fn advance(&mut self) -> Option<u64> {
+        if !self.is_playing() {
+            return None;
+        }
+        let due = (self.ticks as u64 >= self.next_tick).then(|| {
+            self.next_tick += 1;
+            self.next_tick - 1
+        });
+        self.ticks += self.bpm as f64 * PPQN as f64 / (60.0 * SAMPLE_RATE as f64);
+        due
+    }
+}
@@ -1,21 +1,37 @@
+use crate::audio::PlaybackStatus;
 use crate::drums::DrumPad;
-use crate::gemini_player::GeminiPlayer;
-use crate::keyboard::Keyboard;
-use crate::recorder::Recorder;
+use crate::envelope::{Envelope, EnvelopeParam};
+use crate::gemini_player::{GeminiPlayer, MelodyPattern};
+use crate::instrument::InstrumentType;
+use crate::keyboard::{self, Keyboard};
+use crate::looper::Looper;
+use crate::project::{self, Project};
+use crate::quantize::Quantize;
+use crate::recorder::{Recorder, Take};
+use crate::sequencer::{self, DrumPosition, StepSequencer, Velocity};
+use crate::settings::Settings;
+use crate::sync::SyncMode;
+use crate::transport::{self, Transport, TransportState};
 use crossterm::{
+    event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{
+        disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement, EnterAlternateScreen,
+        LeaveAlternateScreen,
+    },
 };
+use log::warn;
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph, Tabs},
+    widgets::{Block, Borders, Clear, Paragraph, Tabs},
     Terminal,
 };
 use std::io::stdout;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -25,10 +41,13 @@ const ACTIVE_STYLE: Style = Style::new().fg(Color::Green).add_modifier(Modifier:
 const INACTIVE_STYLE: Style = Style::new().fg(Color::Gray);
 const ERROR_STYLE: Style = Style::new().fg(Color::Red).add_modifier(Modifier::BOLD);
 
+const PLAYBACK_SPEEDS: [f32; 7] = [0.5, 0.75, 1.0, 1.25, 1.5, 1.75, 2.0];
+
 pub struct App {
     terminal: Terminal<CrosstermBackend<std::io::Stdout>>,
     keystroke_count: u32,
-    current_instrument: String,
+    pub settings: Settings,
+    pub envelope_param: EnvelopeParam,
     pub keyboard: Keyboard,
     pub recorder: Recorder,
     pub drum_pad: DrumPad,
@@ -40,6 +59,59 @@ pub struct App {
     pub ai_loading: bool,
     pub ai_response: Option<String>,
     pub ai_response_time: Option<Instant>,
+    pub playhead: Option<(Duration, Duration)>,
+    pub notice: Option<(String, Style, Instant)>,
+    pub playback_speed: f32,
+    pub loop_playback: bool,
+    pub key_releases: bool,
+    pub looper: Looper,
+    pub loop_station: bool,
+    pub loop_playhead: Option<(Duration, Duration)>,
+    pub transport: Transport,
+    pub transport_state: TransportState,
+    pub transport_ticks: u64,
+    pub quantize: Quantize,
+    pub sequencer: StepSequencer,
+    pub drum_position: Option<DrumPosition>,
+    /// Where Ctrl+S saves the project and Ctrl+O reopens it from
+    pub project_path: PathBuf,
+    // Melody from an opened project, kept for saving when there is no AI
+    saved_melody: Option<MelodyPattern>,
+    /// Autosaved session waiting on the restore prompt
+    pub pending_restore: Option<Project>,
+    /// MIDI ports notes are coming in from
+    pub midi_inputs: Vec<String>,
+    /// MIDI port notes are going out to
+    pub midi_output: Option<String>,
+    pub midi_picker: Option<MidiPicker>,
+    /// Tempo of the MIDI clock being followed
+    pub sync_bpm: Option<f32>,
+}
+
+/// Output port list, opened with Shift+O. The first entry turns MIDI
+/// output off.
+pub struct MidiPicker {
+    pub ports: Vec<String>,
+    pub selected: usize,
+}
+
+impl MidiPicker {
+    pub fn new(ports: Vec<String>, current: Option<&str>) -> Self {
+        let selected = current
+            .and_then(|current| ports.iter().position(|port| port == current))
+            .map_or(0, |index| index + 1);
+        Self { ports, selected }
+    }
+
+    pub fn move_selection(&mut self, steps: i32) {
+        let entries = self.ports.len() as i32 + 1;
+        self.selected = (self.selected as i32 + steps).rem_euclid(entries) as usize;
+    }
+
+    /// The selected port, or None for off.
+    pub fn choice(&self) -> Option<&String> {
+        self.selected.checked_sub(1).map(|index| &self.ports[index])
+    }
 }
 
 impl App {
@@ -48,6 +120,20 @@ impl App {
         let mut stdout = stdout();
         execute!(stdout, EnterAlternateScreen)?;
 
+        // Ask for key release events where the terminal supports them,
+        // otherwise notes fall back to a fixed-length release
+        let key_releases = matches!(supports_keyboard_enhancement(), Ok(true));
+        if key_releases {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(
+                    KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                        | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                        | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS
+                )
+            )?;
+        }
+
         let backend = CrosstermBackend::new(stdout);
         let terminal = Terminal::new(backend)?;
 
@@ -56,11 +142,16 @@ impl App {
             .map(|key| Some(GeminiPlayer::new(&key)))
             .unwrap_or(None);
 
+        let settings = Settings::load();
+        let mut keyboard = Keyboard::new();
+        keyboard.set_shift(settings.octave, settings.transpose);
+
         Ok(App {
             terminal,
             keystroke_count: 0,
-            current_instrument: "Sine".to_string(),
-            keyboard: Keyboard::new(),
+            settings,
+            envelope_param: EnvelopeParam::Attack,
+            keyboard,
             recorder: Recorder::new(),
             drum_pad: DrumPad::new(),
             gemini_player,
@@ -71,6 +162,27 @@ impl App {
             ai_loading: false,
             ai_response: None,
             ai_response_time: None,
+            playhead: None,
+            notice: None,
+            playback_speed: 1.0,
+            loop_playback: false,
+            key_releases,
+            looper: Looper::new(),
+            loop_station: false,
+            loop_playhead: None,
+            transport: Transport::new(),
+            transport_state: TransportState::Stopped,
+            transport_ticks: 0,
+            quantize: Quantize::new(),
+            sequencer: StepSequencer::new(),
+            drum_position: None,
+            project_path: PathBuf::from(project::DEFAULT_PATH),
+            saved_melody: None,
+            pending_restore: None,
+            midi_inputs: Vec::new(),
+            midi_output: None,
+            midi_picker: None,
+            sync_bpm: None,
         })
     }
 
@@ -100,6 +212,33 @@ impl App {
         }
     }
 
+    pub fn notify(&mut self, message: String, success: bool) {
+        let style = if success { ACTIVE_STYLE } else { ERROR_STYLE };
+        self.notice = Some((message, style, Instant::now()));
+    }
+
+    pub fn change_playback_speed(&mut self, steps: i32) {
+        let index = PLAYBACK_SPEEDS
+            .iter()
+            .position(|&speed| speed == self.playback_speed)
+            .unwrap_or(2) as i32;
+        let index = (index + steps).clamp(0, PLAYBACK_SPEEDS.len() as i32 - 1);
+        self.playback_speed = PLAYBACK_SPEEDS[index as usize];
+    }
+
+    pub fn set_playhead(&mut self, playback: &PlaybackStatus) {
+        self.playhead = if playback.is_playing() {
+            Some((playback.position(), playback.length()))
+        } else {
+            None
+        };
+        self.loop_playhead = playback.loop_playhead();
+        self.transport_state = playback.transport_state();
+        self.transport_ticks = playback.transport_ticks();
+        self.drum_position = playback.drum_position();
+        self.sync_bpm = playback.sync_bpm();
+    }
+
     pub fn finish_ai_loading(&mut self, success: bool, message: &str) {
         self.ai_loading = false;
         self.set_ai_status(Some((
@@ -113,15 +252,37 @@ impl App {
     }
 
     pub fn draw(&mut self) -> Result<(), std::io::Error> {
-        self.keyboard.update();
         self.clear_ai_response();
+        if let Some((_, _, time)) = &self.notice {
+            if time.elapsed() > Duration::from_secs(5) {
+                self.notice = None;
+            }
+        }
 
-        let current_instrument = self.current_instrument.clone();
+        let current_instrument = self.settings.instrument.name();
+        let envelope = self.settings.envelope(self.settings.instrument);
+        let lofi = self.settings.lofi;
         let keystroke_count = self.keystroke_count;
-        let is_recording = self.recorder.is_recording;
+        let is_recording = self.recorder.is_recording || self.looper.is_recording;
+        let loop_station = self.loop_station;
+        let loop_tracks = self.loop_tracks();
         let is_drum_mode = self.drum_pad.is_drum_mode;
         let keyboard_keys = self.keyboard.keys.clone();
-        let active_beats = self.drum_pad.active_beats.clone();
+        let drum_pads = self.drum_pad.pads();
+        // Pads light up for live hits and for steps the pattern is playing
+        let mut active_beats = self.drum_pad.active_beats.clone();
+        if let Some(position) = self.drum_position {
+            let pattern = &self.sequencer.bank.patterns[position.slot];
+            active_beats.extend(
+                drum_pads
+                    .iter()
+                    .map(|(key, _, _)| *key)
+                    .filter(|&key| pattern.step(key, position.step) != Velocity::Off),
+            );
+        }
+        let step_grid =
+            (is_drum_mode && self.sequencer.visible).then(|| self.step_grid(&drum_pads));
+        let kit_name = self.drum_pad.kit_name().to_string();
 
         self.terminal.draw(|frame| {
             let size = frame.size();
@@ -132,7 +293,7 @@ impl App {
                 .constraints([
                     Constraint::Length(3), // Title bar [0]
                     Constraint::Length(2), // Mode tabs [1]
-                    Constraint::Length(7), // Info bar [2]
+                    Constraint::Length(9), // Info bar [2]
                     Constraint::Length(4), // AI Response area [3]
                     Constraint::Min(12),   // Main content [4]
                     Constraint::Length(3), // Status bar [5]
@@ -152,9 +313,15 @@ impl App {
             frame.render_widget(title_widget, chunks[0]);
 
             // Mode tabs
-            let titles = vec!["Keyboard Mode", "Drum Mode"];
+            let titles = vec!["Keyboard Mode", "Drum Mode", "Loop Station"];
             let tabs = Tabs::new(titles)
-                .select(if is_drum_mode { 1 } else { 0 })
+                .select(if is_drum_mode {
+                    1
+                } else if loop_station {
+                    2
+                } else {
+                    0
+                })
                 .style(INACTIVE_STYLE)
                 .highlight_style(HIGHLIGHT_STYLE)
                 .divider(symbols::line::VERTICAL);
@@ -164,15 +331,149 @@ impl App {
             let mut info_text = vec![
                 Line::from(vec![
                     Span::styled("Instrument: ", INACTIVE_STYLE),
-                    Span::styled(&current_instrument, HIGHLIGHT_STYLE),
+                    Span::styled(current_instrument, HIGHLIGHT_STYLE),
+                    Span::styled(if lofi { " (lo-fi)" } else { "" }, INACTIVE_STYLE),
+                    Span::styled("  Kit: ", INACTIVE_STYLE),
+                    Span::styled(&kit_name, HIGHLIGHT_STYLE),
+                    Span::styled("  Octave: ", INACTIVE_STYLE),
+                    Span::styled(format!("{:+}", self.keyboard.octave), HIGHLIGHT_STYLE),
+                    Span::styled("  Transpose: ", INACTIVE_STYLE),
+                    Span::styled(format!("{:+}", self.keyboard.transpose), HIGHLIGHT_STYLE),
+                    Span::styled("  Keystrokes: ", INACTIVE_STYLE),
+                    Span::styled(keystroke_count.to_string(), HIGHLIGHT_STYLE),
                 ]),
+                Line::from(
+                    std::iter::once(Span::styled("Envelope:", INACTIVE_STYLE))
+                        .chain(EnvelopeParam::ALL.iter().map(|&param| {
+                            Span::styled(
+                                format!(" {} {}", param.label(), envelope.display(param)),
+                                if param == self.envelope_param {
+                                    HIGHLIGHT_STYLE
+                                } else {
+                                    INACTIVE_STYLE
+                                },
+                            )
+                        }))
+                        .collect::<Vec<_>>(),
+                ),
                 Line::from(vec![
                     Span::styled("Status: ", INACTIVE_STYLE),
-                    if is_recording {
+                    if self.recorder.is_counting_in() {
+                        Span::styled("◔ Count-in", HIGHLIGHT_STYLE)
+                    } else if is_recording {
                         Span::styled("⏺ Recording", ERROR_STYLE)
                     } else {
                         Span::styled("◯ Ready", ACTIVE_STYLE)
                     },
+                    Span::styled("  Take: ", INACTIVE_STYLE),
+                    Span::styled(self.recorder.take_label(), HIGHLIGHT_STYLE),
+                    if self.recorder.overdub {
+                        Span::styled(" Overdub", ERROR_STYLE)
+                    } else {
+                        Span::raw("")
+                    },
+                    Span::styled("  Speed: ", INACTIVE_STYLE),
+                    Span::styled(format!("{:.2}x", self.playback_speed), HIGHLIGHT_STYLE),
+                    Span::styled("  Loop: ", INACTIVE_STYLE),
+                    if self.loop_playback {
+                        Span::styled("On", HIGHLIGHT_STYLE)
+                    } else {
+                        Span::styled("Off", INACTIVE_STYLE)
+                    },
+                    Span::styled("  Quantize: ", INACTIVE_STYLE),
+                    Span::styled(
+                        format!(
+                            "{} {} {:.0}% swing {:.0}%",
+                            if self.quantize.enabled { "On" } else { "Off" },
+                            self.quantize.grid.label(),
+                            self.quantize.strength * 100.0,
+                            self.quantize.swing * 100.0
+                        ),
+                        if self.quantize.enabled {
+                            HIGHLIGHT_STYLE
+                        } else {
+                            INACTIVE_STYLE
+                        },
+                    ),
+                    match &self.notice {
+                        _ if self.pending_restore.is_some() => {
+                            Span::styled("  Restore the last session? (Y/N)", HIGHLIGHT_STYLE)
+                        }
+                        Some((message, style, _)) => Span::styled(format!("  {}", message), *style),
+                        None => Span::raw(""),
+                    },
+                ]),
+                Line::from(vec![
+                    Span::styled("Transport: ", INACTIVE_STYLE),
+                    Span::styled(
+                        format!(
+                            "{} {}",
+                            self.transport_state.symbol(),
+                            self.transport.position(self.transport_ticks)
+                        ),
+                        if self.transport_state == TransportState::Playing {
+                            ACTIVE_STYLE
+                        } else {
+                            INACTIVE_STYLE
+                        },
+                    ),
+                    Span::styled("  Tempo: ", INACTIVE_STYLE),
+                    Span::styled(format!("{:.0} BPM", self.transport.bpm), HIGHLIGHT_STYLE),
+                    Span::styled("  Sync: ", INACTIVE_STYLE),
+                    match (self.settings.sync, self.sync_bpm) {
+                        (SyncMode::Internal, _) => Span::styled("Internal", INACTIVE_STYLE),
+                        (SyncMode::Slave, Some(bpm)) => Span::styled(
+                            format!("{} ({:.1} BPM)", SyncMode::Slave.label(), bpm),
+                            ACTIVE_STYLE,
+                        ),
+                        (SyncMode::Slave, None) => Span::styled(
+                            format!("{} (waiting)", SyncMode::Slave.label()),
+                            HIGHLIGHT_STYLE,
+                        ),
+                        (mode, _) => Span::styled(mode.label(), HIGHLIGHT_STYLE),
+                    },
+                    Span::styled("  Time: ", INACTIVE_STYLE),
+                    Span::styled(self.transport.time_signature.to_string(), HIGHLIGHT_STYLE),
+                    Span::styled("  Click: ", INACTIVE_STYLE),
+                    if self.settings.metronome {
+                        Span::styled(
+                            format!("On {:.0}%", self.settings.metronome_volume * 100.0),
+                            HIGHLIGHT_STYLE,
+                        )
+                    } else {
+                        Span::styled("Off", INACTIVE_STYLE)
+                    },
+                    Span::styled("  Count-in: ", INACTIVE_STYLE),
+                    Span::styled(
+                        match self.settings.count_in {
+                            0 => "Off".to_string(),
+                            1 => "1 bar".to_string(),
+                            bars => format!("{} bars", bars),
+                        },
+                        HIGHLIGHT_STYLE,
+                    ),
+                    Span::styled("  MIDI in: ", INACTIVE_STYLE),
+                    if self.midi_inputs.is_empty() {
+                        Span::styled("Off", INACTIVE_STYLE)
+                    } else {
+                        Span::styled(self.midi_inputs.join(", "), HIGHLIGHT_STYLE)
+                    },
+                    Span::styled("  MIDI out: ", INACTIVE_STYLE),
+                    match &self.midi_output {
+                        Some(port) => Span::styled(
+                            format!(
+                                "{} ({})",
+                                port,
+                                if self.settings.midi_only {
+                                    "instead"
+                                } else {
+                                    "alongside"
+                                }
+                            ),
+                            HIGHLIGHT_STYLE,
+                        ),
+                        None => Span::styled("Off", INACTIVE_STYLE),
+                    },
                 ]),
                 Line::from(vec![
                     Span::styled("AI Mode: ", INACTIVE_STYLE),
@@ -188,6 +489,21 @@ impl App {
                 ]),
             ];
 
+            if let Some((position, length)) = self.playhead {
+                info_text.push(Line::from(vec![
+                    Span::styled("Playback: ", INACTIVE_STYLE),
+                    Span::styled(playhead_bar(position, length, 20), ACTIVE_STYLE),
+                    Span::styled(
+                        format!(
+                            " {:.1}s / {:.1}s",
+                            position.as_secs_f32(),
+                            length.as_secs_f32()
+                        ),
+                        INACTIVE_STYLE,
+                    ),
+                ]));
+            }
+
             // Add AI status message if present
             if let Some((message, style)) = &self.ai_status {
                 info_text.push(Line::from(Span::styled(message, *style)));
@@ -225,24 +541,18 @@ impl App {
 
             // Main content area
             let main_area = chunks[4];
-            if is_drum_mode {
+            if let Some(step_grid) = step_grid {
+                frame.render_widget(step_grid, main_area);
+            } else if is_drum_mode {
                 // Render drum pads
-                let drums = vec![
-                    ('z', "KICK", "💥"),
-                    ('x', "SNARE", "🥁"),
-                    ('c', "HIHAT", "🎪"),
-                    ('v', "CLAP", "👏"),
-                    ('b', "CYMBAL", "🔊"),
-                ];
-
-                let pad_width = main_area.width / 5;
+                let pad_width = main_area.width / drum_pads.len() as u16;
                 let pad_height = main_area.height / 2;
 
-                for (i, (key, name, symbol)) in drums.iter().enumerate() {
+                for (i, (key, name, symbol)) in drum_pads.iter().enumerate() {
                     let x = (i as u16 * pad_width) + 1;
                     let y = main_area.height - pad_height - 1;
 
-                    let style = if active_beats.contains(&key) {
+                    let style = if active_beats.contains(key) {
                         ACTIVE_STYLE
                     } else {
                         INACTIVE_STYLE
@@ -258,12 +568,21 @@ impl App {
                     frame.render_widget(pad_block, pad_area);
                 }
             } else {
+                if loop_station {
+                    let loops_area = Rect::new(
+                        main_area.x + 1,
+                        main_area.y,
+                        main_area.width.saturating_sub(2),
+                        main_area.height - main_area.height / 2 - 1,
+                    );
+                    frame.render_widget(loop_tracks, loops_area);
+                }
+
                 // Render keyboard
-                let keys = vec!['a', 's', 'd', 'f', 'g', 'h', 'j', 'k', 'l'];
-                let key_width = main_area.width / 9;
+                let key_width = main_area.width / keyboard::LAYOUT.len() as u16;
                 let key_height = main_area.height / 2;
 
-                for (i, &key) in keys.iter().enumerate() {
+                for (i, &(key, _)) in keyboard::LAYOUT.iter().enumerate() {
                     let key_info = &keyboard_keys[&key];
                     let x = (i as u16 * key_width) + 1;
                     let y = main_area.height - key_height - 1;
@@ -277,7 +596,7 @@ impl App {
                     let key_block = Block::default()
                         .borders(Borders::ALL)
                         .style(style)
-                        .title(format!("{} {}", key_info.note, key.to_uppercase()));
+                        .title(format!("{} {}", key_info.name, key.to_uppercase()));
 
                     let key_area =
                         Rect::new(main_area.x + x, main_area.y + y, key_width - 1, key_height);
@@ -286,7 +605,7 @@ impl App {
             }
 
             // Status bar
-            let controls = vec![
+            let mut controls = vec![
                 ("TAB", "Switch Mode"),
                 (
                     "1-4",
@@ -297,10 +616,52 @@ impl App {
                     },
                 ),
                 ("M", "AI Mode"),
+            ];
+            if is_drum_mode {
+                controls.extend([("N", "Next Kit"), ("E", "Step Grid")]);
+                if self.sequencer.visible {
+                    controls.extend([
+                        ("ARROWS", "Move"),
+                        ("ENTER", "Step"),
+                        ("F1-F8", "Pattern"),
+                        ("I/BKSP", "Song +/-"),
+                        ("F9", "Song Mode"),
+                        ("SHIFT+A", "Accent"),
+                        ("U/SHIFT+U", "Euclid/Rotate"),
+                        ("SHIFT+H", "Humanize"),
+                        ("SHIFT+F", "Fill"),
+                        (", .", "Length"),
+                    ]);
+                }
+            } else if loop_station {
+                controls.extend([("R", "Record Loop"), ("F1-F4", "Mute"), ("F5-F8", "Clear")]);
+            } else {
+                controls.extend([("5", "Lo-fi"), ("E/[ ]", "Envelope")]);
+            }
+            if !is_drum_mode {
+                controls.extend([("Z/X", "Octave"), ("C/V", "Transpose")]);
+            }
+            controls.extend([
+                ("SPACE", "Play/Pause"),
+                ("ESC", "Stop"),
+                ("- =", "BPM"),
+                ("SHIFT+K", "Sync"),
+                ("T", "Tap"),
+                ("SHIFT+M", "Click"),
+                ("SHIFT+Q", "Quantize"),
                 ("R", "Record"),
+                ("SHIFT+D", "Overdub"),
+                ("( )", "Take"),
+                ("CTRL+Z/Y", "Undo/Redo"),
+                ("CTRL+S/O", "Save/Open"),
                 ("P", "Play"),
+                ("O", "Loop"),
+                ("< >", "Speed"),
+                ("W", "Export WAV"),
+                ("SHIFT+X", "Export MIDI"),
+                ("SHIFT+O", "MIDI Out"),
                 ("Q", "Quit"),
-            ];
+            ]);
             let status_text = controls
                 .into_iter()
                 .map(|(key, action)| {
@@ -319,13 +680,335 @@ impl App {
                 .style(INACTIVE_STYLE)
                 .block(Block::default().borders(Borders::ALL));
             frame.render_widget(status, chunks[5]);
+
+            if let Some(picker) = &self.midi_picker {
+                let mut lines = vec![
+                    Line::from(Span::styled(
+                        format!(
+                            "TAB: {}",
+                            if self.settings.midi_only {
+                                "Instead of the synth"
+                            } else {
+                                "Alongside the synth"
+                            }
+                        ),
+                        HIGHLIGHT_STYLE,
+                    )),
+                    Line::from(""),
+                ];
+                let entries = std::iter::once("Off").chain(picker.ports.iter().map(String::as_str));
+                for (i, entry) in entries.enumerate() {
+                    lines.push(if i == picker.selected {
+                        Line::from(Span::styled(format!("> {}", entry), ACTIVE_STYLE))
+                    } else {
+                        Line::from(Span::styled(format!("  {}", entry), INACTIVE_STYLE))
+                    });
+                }
+
+                let area = frame.size();
+                let width = area.width.min(50);
+                let height = area.height.min(lines.len() as u16 + 2);
+                let popup = Rect::new(
+                    area.x + (area.width - width) / 2,
+                    area.y + (area.height - height) / 2,
+                    width,
+                    height,
+                );
+                let picker_widget = Paragraph::new(lines).block(
+                    Block::default()
+                        .title(" MIDI Output (ENTER: Select, ESC: Close) ")
+                        .borders(Borders::ALL)
+                        .border_style(TITLE_STYLE),
+                );
+                frame.render_widget(Clear, popup);
+                frame.render_widget(picker_widget, popup);
+            }
         })?;
 
         Ok(())
     }
 
-    pub fn set_instrument(&mut self, instrument: &str) {
-        self.current_instrument = instrument.to_string();
+    /// Pad key under the step grid cursor.
+    pub fn grid_key(&self) -> Option<char> {
+        self.drum_pad
+            .pads()
+            .get(self.sequencer.cursor_row)
+            .map(|(key, _, _)| *key)
+    }
+
+    fn step_grid(&self, pads: &[(char, String, &'static str)]) -> Paragraph<'static> {
+        let pattern = self.sequencer.pattern();
+        // Only light the playing step if it's this pattern that's playing
+        let playing_step = self
+            .drum_position
+            .filter(|position| position.slot == self.sequencer.current)
+            .map(|position| position.step);
+        let mut lines = pads
+            .iter()
+            .enumerate()
+            .map(|(row, (key, name, _))| {
+                let mut spans = vec![Span::styled(
+                    format!("{:>8} {} ", name, key.to_uppercase()),
+                    if row == self.sequencer.cursor_row {
+                        HIGHLIGHT_STYLE
+                    } else {
+                        INACTIVE_STYLE
+                    },
+                )];
+                for step in 0..pattern.length {
+                    if step % 4 == 0 {
+                        spans.push(Span::styled("│", INACTIVE_STYLE));
+                    }
+                    let velocity = pattern.step(*key, step);
+                    let style =
+                        if row == self.sequencer.cursor_row && step == self.sequencer.cursor_step {
+                            HIGHLIGHT_STYLE.add_modifier(Modifier::REVERSED)
+                        } else if Some(step) == playing_step {
+                            ACTIVE_STYLE
+                        } else if velocity == Velocity::Accent {
+                            ERROR_STYLE
+                        } else if velocity != Velocity::Off {
+                            HIGHLIGHT_STYLE
+                        } else {
+                            INACTIVE_STYLE
+                        };
+                    spans.push(Span::styled(velocity.symbol().to_string(), style));
+                }
+                Line::from(spans)
+            })
+            .collect::<Vec<_>>();
+
+        let mut song = vec![Span::styled(
+            if self.sequencer.song_mode {
+                "Song ▶ "
+            } else {
+                "Song: "
+            },
+            if self.sequencer.song_mode {
+                ACTIVE_STYLE
+            } else {
+                INACTIVE_STYLE
+            },
+        )];
+        for (entry, &slot) in self.sequencer.bank.song.iter().enumerate() {
+            let playing = self.sequencer.song_mode
+                && self
+                    .drum_position
+                    .is_some_and(|position| position.entry == entry);
+            song.push(Span::styled(
+                format!("{} ", sequencer::slot_name(slot)),
+                if playing {
+                    ACTIVE_STYLE
+                } else {
+                    HIGHLIGHT_STYLE
+                },
+            ));
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from(song));
+
+        let mut title = format!(
+            "Pattern {} ({} steps)",
+            sequencer::slot_name(self.sequencer.current),
+            pattern.length
+        );
+        if let Some(position) = self.drum_position {
+            if position.slot != self.sequencer.current {
+                title.push_str(&format!(
+                    " - playing {}",
+                    sequencer::slot_name(position.slot)
+                ));
+            }
+        }
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title))
+    }
+
+    fn loop_tracks(&self) -> Paragraph<'static> {
+        let mut lines = (0..self.looper.max_loops)
+            .map(|i| {
+                let label = Span::styled(format!("F{} Loop {}: ", i + 1, i + 1), INACTIVE_STYLE);
+                let recording = self.looper.is_recording && self.looper.current_loop == i;
+                let track = match self.looper.loops.get(i) {
+                    _ if recording => Span::styled("⏺ Recording", ERROR_STYLE),
+                    Some(track) if !track.notes.is_empty() => Span::styled(
+                        format!(
+                            "{} {} ({} notes)",
+                            if track.is_active { "▶" } else { "■" },
+                            track.instrument.name(),
+                            track.notes.len()
+                        ),
+                        if track.is_active {
+                            ACTIVE_STYLE
+                        } else {
+                            INACTIVE_STYLE
+                        },
+                    ),
+                    _ => Span::styled("empty", INACTIVE_STYLE),
+                };
+                Line::from(vec![label, track])
+            })
+            .collect::<Vec<_>>();
+
+        if let Some((position, length)) = self.loop_playhead {
+            lines.push(Line::from(vec![
+                Span::styled(playhead_bar(position, length, 20), ACTIVE_STYLE),
+                Span::styled(format!(" {:.1}s", length.as_secs_f32()), INACTIVE_STYLE),
+            ]));
+        }
+
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Loops"))
+    }
+
+    pub fn set_instrument(&mut self, instrument: InstrumentType) {
+        self.settings.instrument = instrument;
+        self.recorder.record_instrument(instrument);
+        self.save_settings();
+    }
+
+    pub fn toggle_lofi(&mut self) {
+        self.settings.lofi = !self.settings.lofi;
+        self.save_settings();
+    }
+
+    /// Records the port MIDI is now going out to, or None once it's off.
+    pub fn set_midi_output(&mut self, port: Option<String>) {
+        self.settings.midi_output = port.clone();
+        self.midi_output = port;
+        self.save_settings();
+    }
+
+    /// Moves the keyboard by octaves and semitones and keeps the setting.
+    pub fn shift_keyboard(&mut self, octaves: i32, semitones: i32) {
+        self.keyboard.set_shift(
+            self.keyboard.octave + octaves,
+            self.keyboard.transpose + semitones,
+        );
+        self.settings.octave = self.keyboard.octave;
+        self.settings.transpose = self.keyboard.transpose;
+        self.save_settings();
+    }
+
+    pub fn next_sync(&mut self) {
+        self.settings.sync = self.settings.sync.next();
+        self.save_settings();
+    }
+
+    pub fn toggle_midi_only(&mut self) {
+        self.settings.midi_only = !self.settings.midi_only;
+        self.save_settings();
+    }
+
+    /// The take as it should be heard: quantized when quantize is on,
+    /// otherwise the raw recording.
+    pub fn current_take(&self) -> Take {
+        let take = self.recorder.take();
+        if self.quantize.enabled {
+            self.quantize.apply(&take, self.transport.bpm)
+        } else {
+            take
+        }
+    }
+
+    /// Snapshot of the session for saving.
+    pub fn project(&self) -> Project {
+        Project {
+            version: project::VERSION,
+            bpm: self.transport.bpm,
+            time_signature: self.transport.time_signature,
+            instrument: self.settings.instrument,
+            envelopes: self.settings.envelopes.clone(),
+            lofi: self.settings.lofi,
+            takes: self.recorder.takes.clone(),
+            current_take: self.recorder.current,
+            loops: self.looper.loops.clone(),
+            loop_length: self.looper.length,
+            loop_bpm: self.looper.bpm,
+            drums: self.sequencer.bank.clone(),
+            song_mode: self.sequencer.song_mode,
+            melody: self
+                .gemini_player
+                .as_ref()
+                .and_then(GeminiPlayer::pattern)
+                .or(self.saved_melody.as_ref())
+                .cloned(),
+        }
+    }
+
+    /// Replaces the session with a saved project. The audio engine still
+    /// needs to be brought in line afterwards.
+    pub fn open_project(&mut self, project: Project) {
+        self.transport.bpm = project.bpm;
+        self.transport.time_signature = project.time_signature;
+        self.settings.instrument = project.instrument;
+        self.settings.envelopes = project.envelopes;
+        self.settings.lofi = project.lofi;
+        self.recorder.restore(project.takes, project.current_take);
+        self.looper
+            .restore(project.loops, project.loop_length, project.loop_bpm);
+        self.sequencer.bank = project.drums;
+        self.sequencer
+            .bank
+            .patterns
+            .resize_with(sequencer::BANK_SLOTS, sequencer::Pattern::new);
+        self.sequencer
+            .bank
+            .song
+            .retain(|&slot| slot < sequencer::BANK_SLOTS);
+        self.sequencer.select(0);
+        self.sequencer.song_mode = project.song_mode;
+        if let (Some(player), Some(melody)) = (&mut self.gemini_player, &project.melody) {
+            player.set_pattern(melody.clone());
+        }
+        self.saved_melody = project.melody;
+    }
+
+    pub fn toggle_metronome(&mut self) {
+        self.settings.metronome = !self.settings.metronome;
+        self.save_settings();
+    }
+
+    pub fn adjust_metronome_volume(&mut self, steps: i32) {
+        let volume = self.settings.metronome_volume + steps as f32 * 0.1;
+        self.settings.metronome_volume = (volume * 10.0).round().clamp(0.0, 10.0) / 10.0;
+        self.save_settings();
+    }
+
+    /// Cycles the count-in between off, one bar and two bars.
+    pub fn next_count_in(&mut self) {
+        self.settings.count_in = (self.settings.count_in + 1) % 3;
+        self.save_settings();
+    }
+
+    /// Tick a count-in started at transport tick `ticks` ends on, and how
+    /// long until then.
+    pub fn count_in(&self, ticks: u64) -> (u64, Duration) {
+        let end =
+            transport::count_in_end(ticks, self.settings.count_in, self.transport.time_signature);
+        (
+            end,
+            transport::ticks_to_duration(end - ticks, self.transport.bpm),
+        )
+    }
+
+    pub fn next_envelope_param(&mut self) {
+        self.envelope_param = self.envelope_param.next();
+    }
+
+    /// Nudges the selected envelope parameter of the current instrument and
+    /// returns the updated envelope.
+    pub fn adjust_envelope(&mut self, steps: i32) -> Envelope {
+        let instrument = self.settings.instrument;
+        let mut envelope = self.settings.envelope(instrument);
+        envelope.adjust(self.envelope_param, steps);
+        self.settings.envelopes.insert(instrument, envelope);
+        self.save_settings();
+        envelope
+    }
+
+    fn save_settings(&self) {
+        if let Err(e) = self.settings.save() {
+            warn!("Failed to save settings: {}", e);
+        }
     }
 
     pub fn log_keystroke(&mut self) {
@@ -333,8 +1016,34 @@ impl App {
     }
 
     pub fn cleanup(&mut self) -> Result<(), std::io::Error> {
-        disable_raw_mode()?;
-        execute!(self.terminal.backend_mut(), LeaveAlternateScreen)?;
-        Ok(())
+        restore_terminal(self.key_releases)
+    }
+}
+
+/// Hands the terminal back in the state the shell expects. Also used by
+/// the panic hook, so it can't rely on the `App`.
+pub fn restore_terminal(key_releases: bool) -> Result<(), std::io::Error> {
+    if key_releases {
+        execute!(stdout(), PopKeyboardEnhancementFlags)?;
     }
+    disable_raw_mode()?;
+    execute!(stdout(), LeaveAlternateScreen)?;
+    Ok(())
+}
+
+fn playhead_bar(position: Duration, length: Duration, width: usize) -> String {
+    let progress = if length.is_zero() {
+        0.0
+    } else {
+        (position.as_secs_f32() / length.as_secs_f32()).min(1.0)
+    };
+    let head = ((width - 1) as f32 * progress) as usize;
+
+    (0..width)
+        .map(|i| match i.cmp(&head) {
+            std::cmp::Ordering::Less => '━',
+            std::cmp::Ordering::Equal => '●',
+            std::cmp::Ordering::Greater => '─',
+        })
+        .collect()
 }